@@ -1,175 +1,245 @@
-use std::{env, thread, time};
-use std::fs::File;
-use crate::core::{CommandId, CoreT, ERROR_FAILED_TO_CREATE_FILE, ERROR_FILE_DOESNT_EXIST, ERROR_INVALID_NUMBER_OF_ARGUMENTS, FtpStatusCode, receive_file, send_file};
-use std::io::{self, BufRead, BufReader};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpStream, UdpSocket};
-use std::io::{Read, Write};
-use std::ops::Add;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, IsTerminal, Write};
 use std::path::Path;
-use std::process::Command;
-use std::str::from_utf8;
-use crate::tcp::tcp::{Tcp};
 use colored::*;
-use crate::tcp::packet::{CommandPacket, FilePacket, FileInfoPacket, ResponseFilePacket, ResponsePacket};
-use std::os::unix::prelude::FileExt;
-use exitcode::OK;
-use num_traits::ToPrimitive;
-use crate::print_exception;
-use crate::udp::udp::{Udp};
-
+use chrono::{Local, LocalResult, TimeZone};
+use crate::client::connection::Connection;
+use crate::client::progress::ProgressBar;
+use crate::error::{Error, Result};
+use crate::core::{CoreT, EntryKind, ERROR_INVALID_NUMBER_OF_ARGUMENTS, ERROR_NAME_NOT_UTF8};
+use crate::tcp::packet::ListEntry;
+
+/// Environment variable read for the password instead of prompting for it.
+pub const PASSWORD_VARIABLE: &str = "FTP_PASSWORD";
+
+/// Interactive front end of a `Connection`, reading commands from the standard input or a script
+/// and printing their outcome.
 pub struct Client {
-    tcp: Tcp,
-    udp: Udp
+    connection: Connection,
+    /// User to log in as when the session starts, prompted for when `None` in interactive mode.
+    username: Option<String>,
+    /// Commands to run instead of reading them from the standard input, see `set_script`.
+    script: Option<VecDeque<String>>,
+    /// Whether the script goes on after a failed command.
+    keep_going: bool,
 }
 
-trait ClientT {}
-
 impl CoreT for Client {
-    fn run(&mut self) -> std::io::Result<()> {
-        loop {
-            let (cmd, args) = self.get_commands();
-            match cmd.to_lowercase().as_ref() {
-                "exit" => {
-                    self.exit();
-                    println!("Closing connection");
-                    break;
-                }
-                "get" => {
-                    self.get(&args);
-                }
-                "put" => {
-                    self.put(&args);
+    fn run(&mut self) -> Result<()> {
+        if let Err(e) = self.login(&self.username.clone().unwrap_or_default()) {
+            if e.is_fatal() || self.script.is_some() {
+                return Err(e);
+            }
+            println!("{} {}", "Error:".red(), e);
+        }
+        let mut failures = 0;
+        let mut last_error = None;
+        while let Some(line) = self.next_command()? {
+            let (cmd, args) = match line.find(' ') {
+                Some(pos) => (&line[0..pos], line[pos + 1..].trim()),
+                None => (line.as_str(), ""),
+            };
+            if cmd.eq_ignore_ascii_case("exit") {
+                break;
+            }
+            match self.execute(cmd, args) {
+                Ok(()) => {}
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) if self.script.is_some() && !self.keep_going => {
+                    // The error itself is reported by the caller.
+                    println!("Stopped after `{}` failed", line);
+                    self.connection.close()?;
+                    return Err(e);
                 }
-                _ => {
-                    println!("{} {}", "Unknown command:".red(), cmd);
-                    continue;
+                Err(e) => {
+                    println!("{} {}", "Error:".red(), e);
+                    if self.script.is_some() {
+                        failures += 1;
+                        last_error = Some(e);
+                    }
                 }
             }
         }
+        self.connection.close()?;
+        println!("Closing connection");
+        if let Some(e) = last_error {
+            println!("{} commands failed", failures);
+            return Err(e);
+        }
         Ok(())
     }
 }
 
-impl ClientT for Client {}
-
 impl Client {
-    pub fn new(ip: String, port: u16) -> std::io::Result<Self> {
+    pub fn new(ip: String, port: u16, block_size: usize, window_size: u64) -> Result<Self> {
         let address = format!("{}:{}", ip, port);
-        let stream = match TcpStream::connect(&address) {
-            Ok(mut stream) => {
-                println!("{} {}", "Host address:".bold(), format!("{}", stream.local_addr().unwrap().to_string()).underline());
-                println!("{} {}", "Successfully connected to server".green().bold(), address.underline());
-                Ok(stream)
-            },
-            Err(e) => {
-                println!("{} {}", "Failed to connect:".red(), e);
-                Err(e)
-            }
-        }?;
-        let udp = UdpSocket::bind(stream.local_addr().unwrap()).expect("Could not bind client socket");
-        udp.connect(stream.peer_addr().unwrap()).expect("Could not connect to server");
-        let client = Client { udp: Udp { socket: udp }, tcp: Tcp { stream } };
-        Ok(client)
+        let connection = Connection::connect(&address, block_size, window_size)?;
+        println!("{} {}", "Host address:".bold(), connection.local_addr()?.to_string().underline());
+        println!("{} {}", "Successfully connected to server".green().bold(), address.underline());
+        println!("{} {} bytes", "Block size:".bold(), connection.block_size());
+        println!("{} {} blocks", "Window size:".bold(), connection.window_size());
+        Ok(Client { connection, username: None, script: None, keep_going: false })
     }
 
-    fn put(&mut self, file_path: &str) -> std::io::Result<()> {
-        let args: Vec<&str> = file_path.split_whitespace().collect();
-        if args.len() != 1 {
-            println!("{} {}", "Error:".red(), ERROR_INVALID_NUMBER_OF_ARGUMENTS);
-            return Ok(());
-        }
-
-        let path = Path::new(file_path);
-        if !path.exists()  || !path.is_file() {
-            println!("{} {}", "Error:".red(), ERROR_FILE_DOESNT_EXIST);
-            return Ok(());
-        }
-
-        let file_name = format!("{}", path.file_name().unwrap().to_str().unwrap());
-
-        self.tcp.write(&CommandPacket::new(CommandId::Put));
+    /// Logs in as `username` when the session starts instead of prompting for a name.
+    pub fn set_username(&mut self, username: String) {
+        self.username = Some(username);
+    }
 
-        let mut packet = FileInfoPacket { size: path.metadata()?.len(), name: [0; 40] };
-        packet.name[..file_name.len()].copy_from_slice(file_name.as_bytes());
-        self.tcp.write(&packet);
+    /// Runs `commands` and exits rather than reading commands from the standard input. The first
+    /// failure ends the session unless `keep_going` is set, and `run` fails if any command did.
+    pub fn set_script(&mut self, commands: Vec<String>, keep_going: bool) {
+        self.script = Some(commands.into());
+        self.keep_going = keep_going;
+    }
 
-        let res = self.tcp.read::<ResponsePacket>();
-        if res.status == FtpStatusCode::Error {
-            println!("{} {}", "Error:".red(), String::from_utf8_lossy(&res.message));
-            return Ok(());
+    /// Next command to run, `None` once the script or the standard input is exhausted.
+    fn next_command(&mut self) -> std::io::Result<Option<String>> {
+        if let Some(script) = &mut self.script {
+            let command = script.pop_front();
+            if let Some(command) = &command {
+                println!("{} {}", "ftp>".bold(), command);
+            }
+            return Ok(command);
         }
-
-        let mut file = match File::open(file_path) {
-            Ok(file) => file,
-            Err(_) => {
-                let mut error_packet = ResponsePacket { status: FtpStatusCode::Error, message: [0; 150] };
-                let error_message = "This file does not exist".to_string();
-                error_packet.message[..ERROR_FAILED_TO_CREATE_FILE.len()].copy_from_slice(ERROR_FAILED_TO_CREATE_FILE.as_bytes());
-                self.tcp.write(&error_packet);
-                return Ok(())
+        loop {
+            match read_line("ftp> ") {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => return Ok(Some(line.trim().to_string())),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
             }
-        };
-        self.tcp.write(&ResponsePacket { status: FtpStatusCode::Ok, message: [0; 150] });
-
-        return send_file(&mut file, &mut self.udp);
+        }
     }
 
-    fn get(&mut self, input: &str) -> std::io::Result<()> {
+    fn execute(&mut self, cmd: &str, input: &str) -> Result<()> {
         let args: Vec<&str> = input.split_whitespace().collect();
-        if args.len() < 1 || args.len() > 2 {
-            println!("{} {}", "Error:".red(), ERROR_INVALID_NUMBER_OF_ARGUMENTS);
-            return Ok(());
+        match cmd.to_lowercase().as_ref() {
+            "login" => return self.login(input),
+            "get" | "reget" => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(invalid_arguments());
+                }
+                let local_path = Path::new(args.get(1).unwrap_or(&args[0]));
+                let mut bar = ProgressBar::new();
+                let result = self.connection.get_file(args[0], local_path, cmd.eq_ignore_ascii_case("reget"), &mut |progress| bar.update(progress));
+                bar.finish();
+                result?;
+            }
+            "put" | "reput" => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(invalid_arguments());
+                }
+                let local_path = Path::new(args[0]);
+                let remote_name = match args.get(1) {
+                    Some(remote_name) => remote_name,
+                    None => local_path.file_name().unwrap_or_default().to_str()
+                        .ok_or_else(|| Error::InvalidRequest(ERROR_NAME_NOT_UTF8.to_string()))?,
+                };
+                let mut bar = ProgressBar::new();
+                let result = self.connection.put_file(local_path, remote_name, cmd.eq_ignore_ascii_case("reput"), &mut |progress| bar.update(progress));
+                bar.finish();
+                result?;
+            }
+            "cd" => {
+                let [path] = args[..] else { return Err(invalid_arguments()) };
+                println!("{}", self.connection.change_dir(path)?.bold());
+            }
+            "pwd" => println!("{}", self.connection.change_dir(".")?.bold()),
+            "ls" => {
+                if args.len() > 1 {
+                    return Err(invalid_arguments());
+                }
+                print_entries(&self.connection.list(args.first().copied().unwrap_or(""))?);
+            }
+            "mkdir" | "rmdir" | "delete" | "rm" => {
+                let [path] = args[..] else { return Err(invalid_arguments()) };
+                match cmd.to_lowercase().as_ref() {
+                    "mkdir" => self.connection.make_dir(path)?,
+                    "rmdir" => self.connection.remove_dir(path)?,
+                    _ => self.connection.delete(path)?,
+                }
+                println!("{}", "Ok".green());
+            }
+            "rename" | "mv" => {
+                let [from, to] = args[..] else { return Err(invalid_arguments()) };
+                self.connection.rename(from, to)?;
+                println!("{}", "Ok".green());
+            }
+            _ => return Err(Error::InvalidRequest(format!("Unknown command: {}", cmd))),
         }
+        Ok(())
+    }
 
-        self.tcp.write(&CommandPacket::new(CommandId::Get));
-
-        let mut packet = FileInfoPacket { size: 0, name: [0; 40] };
-        packet.name[..args[0].len()].copy_from_slice(args[0].as_bytes());
-        self.tcp.write(&packet);
-
-        let res = self.tcp.read::<ResponsePacket>();
-        if res.status == FtpStatusCode::Error {
-            println!("{} {}", "Error:".red(), String::from_utf8_lossy(&res.message));
-            return Ok(());
+    /// An empty name stays unauthenticated, the server may still grant anonymous access. The
+    /// password is taken from `PASSWORD_VARIABLE` when it is set.
+    fn login(&mut self, input: &str) -> Result<()> {
+        let args: Vec<&str> = input.split_whitespace().collect();
+        if args.len() > 1 {
+            return Err(invalid_arguments());
         }
 
-        let mut locations = std::fs::canonicalize("./").unwrap().to_str().unwrap().to_string();
-        if args.len() == 2 {
-            locations = format!("{}/{}", locations, args[1]);
-        } else {
-            locations = format!("{}/{}", locations, args[0]);
+        let username = match args.first() {
+            Some(name) => name.to_string(),
+            None if self.script.is_some() => return Ok(()),
+            None => read_line("Name: ")?,
+        };
+        if username.is_empty() {
+            return Ok(());
         }
-
-
-        let mut file = match std::fs::File::create(locations) {
-            Ok(file) => file,
-            Err(e) => {
-                let mut error_packet = ResponsePacket { status: FtpStatusCode::Error, message: [0; 150] };
-                error_packet.message[..ERROR_FAILED_TO_CREATE_FILE.len()].copy_from_slice(ERROR_FAILED_TO_CREATE_FILE.as_bytes());
-                self.tcp.write(&error_packet);
-                return Ok(())
-            }
+        let password = match std::env::var(PASSWORD_VARIABLE) {
+            Ok(password) => password,
+            Err(_) => read_password("Password: ")?,
         };
-        self.tcp.write(&ResponsePacket{ status: FtpStatusCode::Ok, message: [0; 150] });
-        return receive_file(&mut file,&mut self.udp);
+        self.connection.login(&username, &password)?;
+        println!("{} {}", "Logged in as".green(), username.bold());
+        Ok(())
+    }
+}
+
+/// Fails with `UnexpectedEof` once the standard input is closed. The prompt goes to the standard
+/// error so that it never mixes with output meant for a file or a pipe.
+fn read_line(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut buf = String::new();
+    if io::stdin().read_line(&mut buf)? == 0 {
+        eprintln!();
+        return Err(io::Error::from(ErrorKind::UnexpectedEof));
     }
+    return Ok(buf.trim_end_matches(['\r', '\n']).to_string());
+}
 
-    fn exit(&mut self) {
-        self.tcp.write(&CommandPacket::new(CommandId::Exit));
+/// Reads a password without echoing it, or a plain line when the input is not a terminal.
+pub fn read_password(prompt: &str) -> io::Result<String> {
+    if io::stdin().is_terminal() {
+        return rpassword::prompt_password(prompt);
     }
+    return read_line(prompt);
+}
 
-    fn get_commands(&self) -> (String, String) {
-        print!("ftp> ");
-        io::stdout().flush().unwrap();
-        let mut buf = String::new();
-        io::stdin().read_line(&mut buf).unwrap();
-        let line = buf.trim();
-        let (cmd, args) = match line.find(' ') {
-            Some(pos) => (&line[0..pos], &line[pos + 1..]),
-            None => (line, "".as_ref()),
+fn invalid_arguments() -> Error {
+    return Error::InvalidRequest(ERROR_INVALID_NUMBER_OF_ARGUMENTS.to_string());
+}
+
+fn print_entries(entries: &[ListEntry]) {
+    println!("{}", format!("{:<4} {:>12}  {:<16}  {}", "Type", "Size", "Modified", "Name").bold());
+    for entry in entries {
+        let kind = match entry.kind {
+            EntryKind::File => "file",
+            EntryKind::Directory => "dir",
+            EntryKind::Symlink => "link",
+            EntryKind::Other => "?",
+        };
+        let modified = match Local.timestamp_opt(entry.modified, 0) {
+            LocalResult::Single(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+            _ => "-".to_string(),
+        };
+        let name = match entry.kind {
+            EntryKind::Directory => entry.name.blue().bold(),
+            _ => entry.name.normal(),
         };
-        let s1 = format!("{}", cmd);
-        let s2 = format!("{}", args);
-        (s1, s2)
+        println!("{:<4} {:>12}  {:<16}  {}", kind, entry.size, modified, name);
     }
+    println!("{} entries", entries.len());
 }
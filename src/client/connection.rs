@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use crate::error::{Error, Result};
+use crate::core::{accept_resume, Capabilities, CommandId, create_file, ERROR_NOT_A_FILE, ERROR_RESUME_NOT_SUPPORTED, FtpStatusCode, io_error_message, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE, open_partial_file, Progress, propose_resume, receive_file, send_file, TransferConfig};
+use crate::tcp::packet::{CommandPacket, FileInfoPacket, FileStatPacket, HelloPacket, ListEntry, ListPacket, LoginPacket, RenamePacket, ResponsePacket, UdpConfigPacket};
+use crate::tcp::tcp::Tcp;
+use crate::udp::udp::Udp;
+
+/// A session with a server. Every call either succeeds or returns what went wrong, nothing is
+/// printed; remote paths are relative to the server side current directory.
+pub struct Connection {
+    tcp: Tcp,
+    udp: Udp,
+    transfer_config: TransferConfig,
+    /// Features both sides support, see `HelloPacket`.
+    capabilities: Capabilities,
+}
+
+impl Connection {
+    /// Connects, checks that the server speaks the same protocol version and negotiates the
+    /// capabilities, the block size and the window, the server may grant less than `block_size`
+    /// and `window_size`. `block_size` is first brought within `MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE`.
+    pub fn connect<A: ToSocketAddrs>(address: A, block_size: usize, window_size: u64) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        let udp = UdpSocket::bind((stream.local_addr()?.ip(), 0))?;
+        let mut connection = Connection { udp: Udp::new(udp), tcp: Tcp { stream }, transfer_config: TransferConfig::default(), capabilities: Capabilities::empty() };
+
+        connection.tcp.write(&HelloPacket::default())?;
+        let hello = connection.tcp.read::<HelloPacket>()?;
+        hello.check_version()?;
+        connection.capabilities = hello.shared_capabilities();
+        connection.transfer_config.checksum = connection.capabilities.contains(Capabilities::CHECKSUM);
+        let (block_size, window_size) = (block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE), window_size.max(1));
+        let requested = TransferConfig { block_size, window_size, ..connection.transfer_config };
+        connection.tcp.write(&UdpConfigPacket::new(&requested, connection.udp.socket.local_addr()?.port()))?;
+        let agreed = connection.tcp.read::<UdpConfigPacket>()?;
+        // The server may only lower what we asked for, anything else would break the transfers.
+        if !(MIN_BLOCK_SIZE..=block_size).contains(&agreed.packet_size) {
+            return Err(Error::Protocol(format!("Server granted a block size of {} bytes, expected {} to {}", agreed.packet_size, MIN_BLOCK_SIZE, block_size)));
+        }
+        if !(1..=window_size).contains(&agreed.window_size) {
+            return Err(Error::Protocol(format!("Server granted a window of {} blocks, expected 1 to {}", agreed.window_size, window_size)));
+        }
+        if agreed.ack_timeout_ms == 0 || agreed.max_retries == 0 {
+            return Err(Error::Protocol("Server sent a zero acknowledgement timeout or retry count".to_string()));
+        }
+        connection.udp.socket.connect((connection.tcp.stream.peer_addr()?.ip(), agreed.port))?;
+        connection.transfer_config.block_size = agreed.packet_size;
+        connection.transfer_config.window_size = agreed.window_size;
+        // Both sides must give up at the same time, or one could abort while the other still retries.
+        connection.transfer_config.ack_timeout = Duration::from_millis(agreed.ack_timeout_ms);
+        connection.transfer_config.max_retries = agreed.max_retries;
+        Ok(connection)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        return Ok(self.tcp.stream.local_addr()?);
+    }
+
+    pub fn block_size(&self) -> usize {
+        return self.transfer_config.block_size;
+    }
+
+    pub fn window_size(&self) -> u64 {
+        return self.transfer_config.window_size;
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        return self.capabilities;
+    }
+
+    pub fn login(&mut self, username: &str, password: &str) -> Result<()> {
+        self.tcp.write(&CommandPacket::new(CommandId::Login))?;
+        self.tcp.write(&LoginPacket { username: username.to_string(), password: password.to_string() })?;
+        return self.check_response();
+    }
+
+    /// Uploads `local_path` as `remote_name`, continuing a previous partial upload when `resume`
+    /// is set. `progress` is called as the server acknowledges the file.
+    pub fn put_file(&mut self, local_path: &Path, remote_name: &str, resume: bool, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        self.check_resume(resume)?;
+        // Open first so that a missing file or a directory doesn't leave an empty one on the server.
+        let mut file = File::open(local_path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(Error::InvalidRequest(format!("{}: {}", ERROR_NOT_A_FILE, local_path.display())));
+        }
+        let packet = FileInfoPacket::new(remote_name, metadata.len(), resume)?;
+        self.tcp.write(&CommandPacket::new(CommandId::Put))?;
+        self.tcp.write(&packet)?;
+        self.check_response()?;
+        self.tcp.write(&ResponsePacket::ok())?;
+
+        let start_index = if resume { accept_resume(&file, &mut self.tcp, &self.transfer_config)? } else { 0 };
+        return send_file(&mut file, &mut self.udp, &mut self.tcp, &self.transfer_config, start_index, progress);
+    }
+
+    /// Downloads `remote_name` to `local_path`, continuing from the partial local file when
+    /// `resume` is set. A corrupted download is removed, a complete one gets the modification time
+    /// of the remote file. `progress` is called as the file lands on disk.
+    pub fn get_file(&mut self, remote_name: &str, local_path: &Path, resume: bool, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        self.check_resume(resume)?;
+        let packet = FileInfoPacket::new(remote_name, 0, resume)?;
+        self.tcp.write(&CommandPacket::new(CommandId::Get))?;
+        self.tcp.write(&packet)?;
+        self.check_response()?;
+        let stat = self.tcp.read::<FileStatPacket>()?;
+
+        let file = if resume { open_partial_file(local_path) } else { create_file(local_path) };
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                self.tcp.write(&ResponsePacket::new(FtpStatusCode::from_io_error(&e), &io_error_message(&e)))?;
+                return Err(Error::Io(e));
+            }
+        };
+        self.tcp.write(&ResponsePacket::ok())?;
+
+        let start_index = if resume { propose_resume(&file, &mut self.tcp, &self.transfer_config)? } else { 0 };
+        let result = receive_file(&mut file, &mut self.udp, &mut self.tcp, &self.transfer_config, start_index, stat.size, progress);
+        if matches!(result, Err(Error::ChecksumMismatch)) {
+            std::fs::remove_file(local_path)?;
+        }
+        result?;
+        if let Ok(modified) = u64::try_from(stat.modified) {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+        }
+        Ok(())
+    }
+
+    /// Lists a directory, or describes a single file.
+    pub fn list(&mut self, remote_path: &str) -> Result<Vec<ListEntry>> {
+        self.request(CommandId::List, remote_path)?;
+        let mut entries = vec![];
+        loop {
+            let mut chunk = self.tcp.read::<ListPacket>()?;
+            entries.append(&mut chunk.entries);
+            if chunk.is_last {
+                return Ok(entries);
+            }
+        }
+    }
+
+    pub fn delete(&mut self, remote_path: &str) -> Result<()> {
+        return self.request(CommandId::Delete, remote_path);
+    }
+
+    pub fn make_dir(&mut self, remote_path: &str) -> Result<()> {
+        return self.request(CommandId::MakeDir, remote_path);
+    }
+
+    pub fn remove_dir(&mut self, remote_path: &str) -> Result<()> {
+        return self.request(CommandId::RemoveDir, remote_path);
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.tcp.write(&CommandPacket::new(CommandId::Rename))?;
+        self.tcp.write(&RenamePacket { from: from.to_string(), to: to.to_string() })?;
+        return self.check_response();
+    }
+
+    /// Moves into `remote_path` and returns the new current directory, `"."` only queries it.
+    pub fn change_dir(&mut self, remote_path: &str) -> Result<String> {
+        let packet = FileInfoPacket::new(remote_path, 0, false)?;
+        self.tcp.write(&CommandPacket::new(CommandId::ChangeDir))?;
+        self.tcp.write(&packet)?;
+        let res = self.tcp.read::<ResponsePacket>()?;
+        check_status(&res)?;
+        return Ok(res.message());
+    }
+
+    /// Ends the session, the server closes the connection and no other call may follow.
+    pub fn close(&mut self) -> Result<()> {
+        return self.tcp.write(&CommandPacket::new(CommandId::Exit));
+    }
+
+    /// Sends a command taking a single remote path and waits for its verdict.
+    fn request(&mut self, cmd: CommandId, remote_path: &str) -> Result<()> {
+        let packet = FileInfoPacket::new(remote_path, 0, false)?;
+        self.tcp.write(&CommandPacket::new(cmd))?;
+        self.tcp.write(&packet)?;
+        return self.check_response();
+    }
+
+    fn check_resume(&self, resume: bool) -> Result<()> {
+        if resume && !self.capabilities.contains(Capabilities::RESUME) {
+            return Err(Error::InvalidRequest(ERROR_RESUME_NOT_SUPPORTED.to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_response(&mut self) -> Result<()> {
+        return check_status(&self.tcp.read::<ResponsePacket>()?);
+    }
+}
+
+fn check_status(res: &ResponsePacket) -> Result<()> {
+    if res.status == FtpStatusCode::Ok {
+        return Ok(());
+    }
+    return Err(Error::Remote { status: res.status, message: res.message() });
+}
@@ -0,0 +1,104 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+use crate::core::Progress;
+
+/// Delay between two redraws of the bar.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// Weight of the latest sample in the smoothed transfer rate.
+const RATE_SMOOTHING: f64 = 0.3;
+const BAR_WIDTH: usize = 30;
+
+/// Draws the progress of a transfer on a single terminal line, with the current rate and the time
+/// left. Draws nothing when the standard output is not a terminal.
+pub struct ProgressBar {
+    enabled: bool,
+    drawn: bool,
+    started: Instant,
+    last_draw: Instant,
+    last_transferred: Option<u64>,
+    /// Smoothed rate in bytes per second.
+    rate: f64,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        return Self { enabled: io::stdout().is_terminal(), drawn: false, started: now, last_draw: now, last_transferred: None, rate: 0.0 }
+    }
+
+    pub fn update(&mut self, progress: Progress) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let last_transferred = match self.last_transferred {
+            Some(last_transferred) => last_transferred,
+            // A resumed prefix is not part of the rate.
+            None => {
+                self.last_transferred = Some(progress.transferred);
+                self.last_draw = now;
+                return self.draw(progress);
+            }
+        };
+        let elapsed = now.duration_since(self.last_draw);
+        if elapsed < REDRAW_INTERVAL && progress.transferred < progress.total {
+            return;
+        }
+        let sample = progress.transferred.saturating_sub(last_transferred) as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        self.rate = if self.rate == 0.0 { sample } else { RATE_SMOOTHING * sample + (1.0 - RATE_SMOOTHING) * self.rate };
+        self.last_transferred = Some(progress.transferred);
+        self.last_draw = now;
+        self.draw(progress);
+    }
+
+    /// Ends the line of the bar, if anything was drawn.
+    pub fn finish(&mut self) {
+        if self.drawn {
+            println!(" in {}", format_duration(self.started.elapsed()));
+            self.drawn = false;
+        }
+    }
+
+    fn draw(&mut self, progress: Progress) {
+        let ratio = if progress.total == 0 { 1.0 } else { progress.transferred as f64 / progress.total as f64 };
+        let filled = ((ratio * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+        let eta = match self.rate > 0.0 {
+            true => format_duration(Duration::from_secs_f64(progress.total.saturating_sub(progress.transferred) as f64 / self.rate)),
+            false => "--:--".to_string(),
+        };
+        print!("\r[{}{}] {:>3}%  {} / {}  {}/s  ETA {}\x1b[K",
+            "#".repeat(filled), " ".repeat(BAR_WIDTH - filled), (ratio * 100.0) as u32,
+            format_bytes(progress.transferred), format_bytes(progress.total), format_bytes(self.rate as u64), eta);
+        let _ = io::stdout().flush();
+        self.drawn = true;
+    }
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    return match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    return match seconds {
+        0..3600 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        _ => format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60),
+    }
+}
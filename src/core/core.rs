@@ -1,97 +1,541 @@
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
 use std::path::Path;
-use std::time;
-use colored::Colorize;
+use std::time::{Duration, Instant};
+use bitflags::bitflags;
+use log::{debug, warn};
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
 use serde::{Deserialize, Serialize};
-use crate::tcp::packet::{FilePacket, FileInfoPacket, ResponseFilePacket, ResponsePacket};
+use sha2::{Digest, Sha256};
+use crate::tcp::packet::{ChecksumPacket, FilePacket, ResponseFilePacket, ResponsePacket, ResumePacket};
 use crate::tcp::tcp::Tcp;
-use crate::udp::udp::{Udp};
-use std::io::{Read, Write};
+use crate::udp::udp::{MAX_DATAGRAM_SIZE, Udp};
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::FileExt;
-use num_traits::ToPrimitive;
 
 pub trait CoreT {
-    fn run(&mut self) -> std::io::Result<()>;
+    fn run(&mut self) -> Result<()>;
 }
 
+/// Version of the packet layout, a client and a server only talk when theirs match.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+bitflags! {
+    /// Optional features, a feature is used only when both sides announce it in their `HelloPacket`.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct Capabilities: u32 {
+        /// `reget` and `reput`, see `propose_resume` and `accept_resume`.
+        const RESUME = 1 << 0;
+        /// End of transfer SHA-256 verification, see `receive_file`.
+        const CHECKSUM = 1 << 1;
+    }
+}
+
+/// Everything this build supports.
+pub const CAPABILITIES: Capabilities = Capabilities::all();
+
+/// Every command but `Exit` is followed by exactly one request packet on the control channel,
+/// which lets the server reject a command without having to understand its arguments.
 #[derive(Serialize, Deserialize, Debug, FromPrimitive)]
 pub enum CommandId {
     Exit,
     Get,
     Put,
+    List,
+    MakeDir,
+    RemoveDir,
+    Delete,
+    Rename,
+    Login,
+    ChangeDir,
 }
 
-#[derive(Serialize, Deserialize, Debug, FromPrimitive, Eq, PartialEq)]
+/// Outcome of a request, numbered like FTP reply codes: 2xx is a success, 4xx a failure worth
+/// retrying later and 5xx a permanent one. Sent as the bare number.
+#[derive(Serialize, Deserialize, Debug, FromPrimitive, Clone, Copy, Eq, PartialEq)]
+#[serde(into = "u16", try_from = "u16")]
 pub enum FtpStatusCode {
-    Ok,
-    Error,
+    Ok = 200,
+    /// The server is about to close the connection.
+    Closing = 421,
+    /// The data transfer was given up on, a partial file may be resumed.
+    TransferAborted = 426,
+    /// Any other failure on the side that answers.
+    Error = 451,
+    /// The disk or the user's quota is full.
+    InsufficientStorage = 452,
+    /// The path sent by the client is malformed, leads outside of the home directory or has the
+    /// wrong type for the command.
+    InvalidPath = 501,
+    /// The request needs a feature the peer did not announce, see `Capabilities`.
+    NotSupported = 504,
+    /// The command requires a successful `Login` first, or the login was refused.
+    NotLoggedIn = 530,
+    /// The file or directory doesn't exist.
+    NotFound = 550,
+    /// The user's role, or the file system, doesn't allow the command.
+    PermissionDenied = 551,
+    /// The target of a command that creates an entry already exists.
+    AlreadyExists = 553,
 }
 
-pub const FILE_BLOC_SIZE: usize = 1024;
+impl FtpStatusCode {
+    /// Closest status for a failed filesystem operation.
+    pub fn from_io_error(error: &std::io::Error) -> Self {
+        return match error.kind() {
+            ErrorKind::NotFound => FtpStatusCode::NotFound,
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => FtpStatusCode::PermissionDenied,
+            ErrorKind::AlreadyExists => FtpStatusCode::AlreadyExists,
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => FtpStatusCode::InsufficientStorage,
+            ErrorKind::NotADirectory | ErrorKind::IsADirectory | ErrorKind::InvalidInput | ErrorKind::InvalidFilename => FtpStatusCode::InvalidPath,
+            _ => FtpStatusCode::Error,
+        }
+    }
 
-pub fn send_file(file: &mut File, udp: &mut Udp) -> std::io::Result<()> {
-    udp.set_read_timeout(Some(time::Duration::from_secs(3)));
+    /// What is shown when a response carries no message.
+    pub fn description(&self) -> &'static str {
+        return match self {
+            FtpStatusCode::Ok => "Ok",
+            FtpStatusCode::Closing => "Closing connection",
+            FtpStatusCode::TransferAborted => ERROR_TRANSFER_ABORTED,
+            FtpStatusCode::Error => "Request failed",
+            FtpStatusCode::InsufficientStorage => "Not enough storage space",
+            FtpStatusCode::InvalidPath => "Invalid path",
+            FtpStatusCode::NotSupported => "Not supported by the peer",
+            FtpStatusCode::NotLoggedIn => "Not logged in",
+            FtpStatusCode::NotFound => "No such file or directory",
+            FtpStatusCode::PermissionDenied => "Permission denied",
+            FtpStatusCode::AlreadyExists => "File or directory already exists",
+        }
+    }
+}
 
-    let mut offset = 0;
-    let mut file_packet = FilePacket { index: 0, is_last: false, data_size: FILE_BLOC_SIZE, data: [0; FILE_BLOC_SIZE] };
+impl From<FtpStatusCode> for u16 {
+    fn from(status: FtpStatusCode) -> Self {
+        return status as u16;
+    }
+}
 
-    loop {
-        file_packet.data = [0; FILE_BLOC_SIZE];
-        let num_bytes_read = file.read_at(& mut file_packet.data, offset)?;
-        offset += num_bytes_read.to_u64().unwrap();
+impl TryFrom<u16> for FtpStatusCode {
+    type Error = String;
+
+    fn try_from(code: u16) -> std::result::Result<Self, String> {
+        return FtpStatusCode::from_u16(code).ok_or_else(|| format!("Unknown status code {}", code));
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, FromPrimitive, Eq, PartialEq, Clone, Copy)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// Longest path, in bytes, a `FileInfoPacket` may carry.
+pub const MAX_PATH_LENGTH: usize = 4096;
+/// Longest single path component, in bytes, the server accepts.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Number of entries sent in each `ListPacket` of a directory listing.
+pub const LIST_CHUNK_SIZE: usize = 64;
+
+/// Payload size of a `FilePacket` used until client and server negotiate another one.
+pub const DEFAULT_BLOCK_SIZE: usize = 1024;
+/// Window used unless configured otherwise, see `TransferConfig::window_size`.
+pub const DEFAULT_WINDOW_SIZE: u64 = 64;
+/// Most payload bytes a full window may hold, the sender keeps every block in flight in memory.
+pub const MAX_WINDOW_BYTES: u64 = 64 * 1024 * 1024;
+/// Smallest payload size a peer may negotiate.
+pub const MIN_BLOCK_SIZE: usize = 512;
+/// Largest payload that still fits a `FilePacket` in a single UDP datagram.
+pub const MAX_BLOCK_SIZE: usize = MAX_DATAGRAM_SIZE - FILE_PACKET_OVERHEAD;
+/// Bytes a serialized `FilePacket` adds to its payload: index, last block flag and payload length.
+const FILE_PACKET_OVERHEAD: usize = 8 + 1 + 8;
+
+/// Tuning of the windowed UDP transfer shared by `send_file` and `receive_file`.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferConfig {
+    /// Payload size of every block but the last, as negotiated with a `UdpConfigPacket`.
+    pub block_size: usize,
+    /// Number of blocks, starting at the oldest unacknowledged one, that may be in flight at once.
+    /// Both sides must use the same value, the receiver drops any block beyond its window.
+    pub window_size: u64,
+    /// Delay after which a block that has not been acknowledged is sent again.
+    pub ack_timeout: Duration,
+    /// Number of times a single block is sent before the transfer is aborted.
+    pub max_retries: u32,
+    /// Whether the receiver checks the file against the sender's digest, see `Capabilities::CHECKSUM`.
+    pub checksum: bool,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        return Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            window_size: DEFAULT_WINDOW_SIZE,
+            ack_timeout: Duration::from_millis(300),
+            max_retries: 6,
+            checksum: true,
+        }
+    }
+}
+
+/// How far a transfer got, reported to the callback of `send_file` and `receive_file`.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// Bytes of the file known to be on the receiver's disk, including a resumed prefix.
+    pub transferred: u64,
+    /// Size of the whole file, as announced by the sender when receiving.
+    pub total: u64,
+}
+
+struct InFlightBlock {
+    packet: Vec<u8>,
+    sent_at: Instant,
+    retries: u32,
+}
+
+/// Sends `file` from block `start_index` over `udp`, then its SHA-256 digest over `tcp` and waits
+/// for the receiver's verdict. The digest always covers the whole file, resumed or not, and is left
+/// zeroed when `config.checksum` is off. `progress` is called as blocks get acknowledged.
+pub fn send_file(file: &mut File, udp: &mut Udp, tcp: &mut Tcp, config: &TransferConfig, start_index: u64, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+    let mut hasher = Sha256::new();
+    let prepared = hash_prefix(file, start_index * config.block_size as u64, &mut hasher)
+        .map_err(Error::from)
+        .and_then(|_| discard_stale_packets(udp));
+    // Wait until the receiver has drained its own socket, or it could mistake our blocks for stale ones.
+    let ready = tcp.read::<ResponsePacket>()?;
+    if ready.status != FtpStatusCode::Ok {
+        return Err(Error::Remote { status: ready.status, message: ready.message() });
+    }
+
+    if let Err(e) = prepared.and_then(|_| send_blocks(file, udp, config, start_index, &mut hasher, progress)) {
+        // The receiver still expects the end of transfer on the control channel.
+        tcp.write(&ChecksumPacket { status: FtpStatusCode::TransferAborted, digest: [0; 32] })?;
+        tcp.read::<ResponsePacket>()?;
+        return Err(e);
+    }
+
+    let digest = if config.checksum { hasher.finalize().into() } else { [0; 32] };
+    tcp.write(&ChecksumPacket { status: FtpStatusCode::Ok, digest })?;
+    let res = tcp.read::<ResponsePacket>()?;
+    if res.status != FtpStatusCode::Ok {
+        return Err(Error::Remote { status: res.status, message: res.message() });
+    }
+    Ok(())
+}
+
+/// The UDP part of `send_file`: sends the blocks from `start_index` on until all are acknowledged.
+fn send_blocks(file: &File, udp: &mut Udp, config: &TransferConfig, start_index: u64, hasher: &mut Sha256, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+    // The last block is the first one holding less than block_size bytes, it may be empty.
+    let block_size = config.block_size as u64;
+    let total = file.metadata()?.len();
+    let last_index = total / block_size;
+    let mut acknowledged = start_index * block_size;
+    let mut in_flight: BTreeMap<u64, InFlightBlock> = BTreeMap::new();
+    let mut next_index = start_index;
 
-        if num_bytes_read != FILE_BLOC_SIZE {
-            file_packet.is_last = true;
-            file_packet.data_size = num_bytes_read;
+    loop {
+        let base = in_flight.keys().next().copied().unwrap_or(next_index);
+        while next_index <= last_index && next_index < base + config.window_size {
+            let block = read_block(file, next_index, last_index, config.block_size)?;
+            hasher.update(&block.data);
+            let packet = bincode::serialize(&block)?;
+            udp.write_raw(packet.clone())?;
+            in_flight.insert(next_index, InFlightBlock { packet, sent_at: Instant::now(), retries: 1 });
+            next_index += 1;
         }
 
-        let serialized_file_packet = bincode::serialize(&file_packet).unwrap();
-
-        for retry in 1..7 {
-            udp.write_raw(serialized_file_packet.clone());
-            match udp.read::<ResponseFilePacket>() {
-                Some(raw_packet) => {
-                    // let response_file_packet = bincode::deserialize::<ResponseFilePacket>(&raw_packet[..]).unwrap();
-                    // if response_file_packet.index == file_packet.index {
-                    //     break;
-                    // }
-                    break;
-                },
-                None => {
-                    if retry == 6 {
-                        println!("{} {}", "Error:".red(), "Abort");
-                        // write_message_udp(udp, bincode::serialize(&ResponseFilePacket { index: file_packet.index, status: FtpStatusCode::Error }).unwrap());
-                        return Ok(())
-                    }
-                    println!("{} {} {} {}", "Error:".red(), "Server is not responding: ", retry, " try");
+        let oldest = match in_flight.values().map(|block| block.sent_at).min() {
+            Some(oldest) => oldest,
+            None => return Ok(()),
+        };
+
+        let wait = (oldest + config.ack_timeout).saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            udp.set_read_timeout(Some(wait))?;
+            if let Some(response) = udp.read::<ResponseFilePacket>() {
+                // Acknowledgements for blocks that are not in flight are duplicates and change nothing.
+                if response.status == FtpStatusCode::Ok && in_flight.remove(&response.index).is_some() {
+                    acknowledged = (acknowledged + block_size).min(total);
+                    progress(Progress { transferred: acknowledged, total });
                 }
+                continue;
             }
-
         }
 
-        file_packet.index += 1;
+        let now = Instant::now();
+        for (index, block) in in_flight.iter_mut() {
+            if now < block.sent_at + config.ack_timeout {
+                continue;
+            }
+            if block.retries >= config.max_retries {
+                return Err(Error::Io(std::io::Error::new(ErrorKind::TimedOut, format!("Block {} was never acknowledged", index))));
+            }
+            debug!("Peer is not responding for block {}: {} try", index, block.retries);
+            udp.write_raw(block.packet.clone())?;
+            block.sent_at = now;
+            block.retries += 1;
+        }
+    }
+}
 
-        if num_bytes_read != FILE_BLOC_SIZE {
+fn read_block(file: &File, index: u64, last_index: u64, block_size: usize) -> std::io::Result<FilePacket> {
+    let mut data = vec![0; block_size];
+    let offset = index * block_size as u64;
+    let mut data_size = 0;
+    while data_size < block_size {
+        let num_bytes_read = file.read_at(&mut data[data_size..], offset + data_size as u64)?;
+        if num_bytes_read == 0 {
             break;
         }
+        data_size += num_bytes_read;
+    }
+    data.truncate(data_size);
+    Ok(FilePacket { index, is_last: index == last_index, data })
+}
+
+/// Receives a file sent by `send_file` from block `start_index` and checks it against the sender's
+/// digest once it is on disk. `file` must be opened for reading as well. A mismatch is reported to
+/// the sender and returned as `Error::ChecksumMismatch`, the caller is then expected to delete the file.
+/// If the sender goes silent the file is cut after its last contiguous block so it can be resumed.
+///
+/// The file is first grown to the `size` announced by the sender, and the transfer is refused
+/// before any block is sent when the disk can't hold it. `progress` is called whenever the
+/// contiguous part of the file on disk grows.
+pub fn receive_file(file: &mut File, udp: &mut Udp, tcp: &mut Tcp, config: &TransferConfig, start_index: u64, size: u64, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+    // The sender waits for our answer whatever happens, and then for one to its checksum.
+    let prepared = discard_stale_packets(udp)
+        .and_then(|_| Ok(file.set_len(start_index * config.block_size as u64)?))
+        .and_then(|_| Ok(preallocate(file, size)?));
+    if let Err(e) = prepared {
+        let status = match &e { Error::Io(e) => FtpStatusCode::from_io_error(e), _ => FtpStatusCode::Error };
+        tcp.write(&ResponsePacket::new(status, &e.to_string()))?;
+        return Err(e);
     }
+    tcp.write(&ResponsePacket::ok())?;
+
+    let received = receive_blocks(file, udp, config, start_index, size, progress);
+    let checksum = tcp.read::<ChecksumPacket>()?;
+    if let Err(e) = received {
+        tcp.write(&ResponsePacket::new(FtpStatusCode::TransferAborted, &e.to_string()))?;
+        return Err(e);
+    }
+    if checksum.status != FtpStatusCode::Ok {
+        tcp.write(&ResponsePacket::new(FtpStatusCode::TransferAborted, ERROR_TRANSFER_ABORTED))?;
+        return Err(Error::Io(std::io::Error::new(ErrorKind::TimedOut, ERROR_TRANSFER_ABORTED)));
+    }
+    if config.checksum && hash_file(file)? != checksum.digest {
+        tcp.write(&ResponsePacket::new(FtpStatusCode::Error, ERROR_CHECKSUM_MISMATCH))?;
+        return Err(Error::ChecksumMismatch);
+    }
+    tcp.write(&ResponsePacket::ok())?;
     Ok(())
 }
 
-pub fn receive_file(file: &mut File, udp: &mut Udp) -> std::io::Result<()> {
-    udp.set_read_timeout(None);
-    loop {
-        let packet = udp.read::<FilePacket>().unwrap();
-        udp.write(&ResponseFilePacket { index: packet.index, status: FtpStatusCode::Ok });
-        file.write(&packet.data[..packet.data_size]);
+/// The UDP part of `receive_file`: writes the blocks from `start_index` on until the last one.
+fn receive_blocks(file: &File, udp: &mut Udp, config: &TransferConfig, start_index: u64, size: u64, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+    let block_size = config.block_size as u64;
+    let idle_timeout = config.ack_timeout * (config.max_retries + 1);
+    udp.set_read_timeout(Some(idle_timeout))?;
+    // Lowest block index not received yet, everything below it is already on disk.
+    let mut expected_index = start_index;
+    let mut received: BTreeSet<u64> = BTreeSet::new();
+    let mut last_index: Option<u64> = None;
+    let mut last_activity = Instant::now();
+
+    while last_index.is_none_or(|last_index| expected_index <= last_index) {
+        let packet = match udp.read::<FilePacket>() {
+            Some(packet) => packet,
+            None if last_activity.elapsed() >= idle_timeout => {
+                file.set_len(expected_index * block_size)?;
+                return Err(Error::Io(std::io::Error::new(ErrorKind::TimedOut, ERROR_TRANSFER_ABORTED)));
+            }
+            None => continue,
+        };
+        last_activity = Instant::now();
+        if !is_valid_block(&packet, expected_index, last_index, config) {
+            debug!("Ignoring unexpected block {}", packet.index);
+            continue;
+        }
+        udp.write(&ResponseFilePacket { index: packet.index, status: FtpStatusCode::Ok })?;
+        if packet.index < expected_index || !received.insert(packet.index) {
+            continue;
+        }
+
+        file.write_all_at(&packet.data, packet.index * block_size)?;
         if packet.is_last {
+            last_index = Some(packet.index);
+            // The announced size is only a hint, the last block tells where the file really ends.
+            file.set_len(packet.index * block_size + packet.data.len() as u64)?;
+        }
+        if received.contains(&expected_index) {
+            while received.remove(&expected_index) {
+                expected_index += 1;
+            }
+            progress(Progress { transferred: (expected_index * block_size).min(size), total: size });
+        }
+    }
+
+    // Keep acknowledging retransmissions for a while in case our last acknowledgements were lost.
+    udp.set_read_timeout(Some(config.ack_timeout * 2))?;
+    while let Some(packet) = udp.read::<FilePacket>() {
+        udp.write(&ResponseFilePacket { index: packet.index, status: FtpStatusCode::Ok })?;
+    }
+    Ok(())
+}
+
+/// Describes a failed filesystem operation in terms a remote user can act on.
+pub fn io_error_message(error: &std::io::Error) -> String {
+    return match error.kind() {
+        ErrorKind::DirectoryNotEmpty => "Directory is not empty".to_string(),
+        ErrorKind::NotADirectory => "Not a directory".to_string(),
+        ErrorKind::IsADirectory => "Is a directory".to_string(),
+        _ => match FtpStatusCode::from_io_error(error) {
+            FtpStatusCode::Error | FtpStatusCode::InvalidPath => error.to_string(),
+            status => status.description().to_string(),
+        },
+    }
+}
+
+/// Creates (or truncates) a file that `receive_file` can both write to and read back for verification.
+pub fn create_file<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
+    return OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path);
+}
+
+/// Reserves the disk space for a file of `size` bytes, failing with `StorageFull` when the
+/// file system doesn't have that much left. Does nothing when the file is already that large.
+fn preallocate(file: &File, size: u64) -> std::io::Result<()> {
+    let current = file.metadata()?.len();
+    if size <= current {
+        return Ok(());
+    }
+    let needed = size - current;
+    let available = available_space(file)?;
+    if needed > available {
+        return Err(std::io::Error::new(ErrorKind::StorageFull, format!("Not enough disk space: {} bytes needed, {} available", needed, available)));
+    }
+    // Not every file system supports it, the space check above is then all there is.
+    return match unsafe { libc::posix_fallocate(file.as_raw_fd(), current as libc::off_t, needed as libc::off_t) } {
+        0 | libc::EOPNOTSUPP | libc::EINVAL => Ok(()),
+        code => Err(std::io::Error::from_raw_os_error(code)),
+    }
+}
+
+/// Bytes an unprivileged user can still write on the file system holding `file`.
+// The `statvfs` field types vary between platforms, hence the casts.
+#[allow(clippy::unnecessary_cast)]
+fn available_space(file: &File) -> std::io::Result<u64> {
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    return Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64));
+}
+
+/// Clamps the block size requested by a client to what this side supports.
+pub fn negotiate_block_size(requested: usize, max_block_size: usize) -> usize {
+    return requested.clamp(MIN_BLOCK_SIZE, max_block_size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE));
+}
+
+/// The window both sides can keep up with, the smaller of the client's and the server's.
+pub fn negotiate_window_size(requested: u64, window_size: u64) -> u64 {
+    return requested.min(window_size).max(1);
+}
+
+/// Opens a file to resume a transfer into, keeping whatever a previous attempt already wrote.
+pub fn open_partial_file<P: AsRef<Path>>(path: P) -> std::io::Result<File> {
+    return OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path);
+}
+
+pub fn hash_file(file: &File) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hash_prefix(file, u64::MAX, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Feeds at most the first `size` bytes of `file` to `hasher` and returns how many there were.
+fn hash_prefix(file: &File, size: u64, hasher: &mut Sha256) -> std::io::Result<u64> {
+    let mut buffer = vec![0; 64 * DEFAULT_BLOCK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let chunk_size = (size - offset).min(buffer.len() as u64) as usize;
+        let num_bytes_read = file.read_at(&mut buffer[..chunk_size], offset)?;
+        if num_bytes_read == 0 {
             break;
         }
+        hasher.update(&buffer[..num_bytes_read]);
+        offset += num_bytes_read as u64;
     }
+    Ok(offset)
+}
+
+/// Receiver side of a resumed transfer: offers to restart after the whole blocks already on disk,
+/// along with their digest, and returns the block index the sender agreed on. The sender may only
+/// accept the offer or restart from the beginning.
+pub fn propose_resume(file: &File, tcp: &mut Tcp, config: &TransferConfig) -> Result<u64> {
+    let block_size = config.block_size as u64;
+    let offset = file.metadata()?.len() / block_size * block_size;
+    let mut hasher = Sha256::new();
+    hash_prefix(file, offset, &mut hasher)?;
+    tcp.write(&ResumePacket { offset, digest: hasher.finalize().into() })?;
+    let agreed = tcp.read::<ResumePacket>()?;
+    if agreed.offset != 0 && agreed.offset != offset {
+        return Err(Error::Protocol(format!("Resume offset {} is neither 0 nor the proposed {}", agreed.offset, offset)));
+    }
+    Ok(agreed.offset / block_size)
+}
+
+/// Sender side of a resumed transfer: accepts the receiver's offer only when our own prefix has the
+/// same digest, otherwise the transfer restarts from the beginning. Returns the agreed block index.
+pub fn accept_resume(file: &File, tcp: &mut Tcp, config: &TransferConfig) -> Result<u64> {
+    let block_size = config.block_size as u64;
+    let proposal = tcp.read::<ResumePacket>()?;
+    let mut hasher = Sha256::new();
+    let prefix_size = hash_prefix(file, proposal.offset, &mut hasher)?;
+    let digest: [u8; 32] = hasher.finalize().into();
+    let offset = if proposal.offset.is_multiple_of(block_size) && prefix_size == proposal.offset && digest == proposal.digest {
+        proposal.offset
+    } else {
+        warn!("{}", WARNING_RESUME_REJECTED);
+        0
+    };
+    tcp.write(&ResumePacket { offset, digest })?;
+    Ok(offset / block_size)
+}
+
+fn is_valid_block(packet: &FilePacket, expected_index: u64, last_index: Option<u64>, config: &TransferConfig) -> bool {
+    if packet.data.len() > config.block_size || packet.index >= expected_index + config.window_size {
+        return false;
+    }
+    if !packet.is_last && packet.data.len() != config.block_size {
+        return false;
+    }
+    return match last_index {
+        Some(last_index) => packet.index < last_index || (packet.index == last_index && packet.is_last),
+        None => true,
+    };
+}
+
+/// Drops datagrams left over from a previous transfer so they are not mistaken for this one's.
+fn discard_stale_packets(udp: &mut Udp) -> Result<()> {
+    udp.set_read_timeout(Some(Duration::from_millis(1)))?;
+    while udp.read_raw().is_some() {}
     Ok(())
 }
 
-pub static ERROR_FAILED_TO_CREATE_FILE: &'static str = "Failed to create file";
-pub static ERROR_FILE_DOESNT_EXIST: &'static str = "File doesn't exist";
-pub static ERROR_INVALID_NUMBER_OF_ARGUMENTS: &'static str = "Invalid number of arguments";
+pub static ERROR_FILE_DOESNT_EXIST: &str = "File doesn't exist";
+pub static ERROR_NOT_A_FILE: &str = "Not a regular file";
+pub static ERROR_PATH_TOO_LONG: &str = "Path is too long";
+pub static ERROR_NAME_TOO_LONG: &str = "File name is too long";
+pub static ERROR_NAME_NOT_UTF8: &str = "File name is not valid UTF-8";
+pub static ERROR_INVALID_NUMBER_OF_ARGUMENTS: &str = "Invalid number of arguments";
+pub static ERROR_CHECKSUM_MISMATCH: &str = "Checksum mismatch, the received file is corrupted";
+pub static ERROR_RESUME_NOT_SUPPORTED: &str = "The peer does not support resuming transfers";
+pub static ERROR_TRANSFER_ABORTED: &str = "Transfer aborted, the peer stopped responding";
+pub static WARNING_RESUME_REJECTED: &str = "Partial file doesn't match the source, restarting from the beginning";
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use crate::core::{ERROR_CHECKSUM_MISMATCH, FtpStatusCode};
+
+/// Everything that can go wrong in a session, on either side.
+#[derive(Debug)]
+pub enum Error {
+    /// A local file or the UDP socket failed, or a transfer was given up on.
+    Io(io::Error),
+    /// The control connection failed or was closed by the peer.
+    Connection(io::Error),
+    /// A control packet could not be decoded.
+    Decode(bincode::Error),
+    /// The peer broke the protocol, e.g. with an oversized frame.
+    Protocol(String),
+    /// The peer refused the request, `message` tells why.
+    Remote { status: FtpStatusCode, message: String },
+    /// The file was transferred but doesn't match the sender's checksum.
+    ChecksumMismatch,
+    /// The request can't be sent as is, e.g. a path that is too long.
+    InvalidRequest(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the control channel can't be trusted anymore, the session must then be closed.
+    pub fn is_fatal(&self) -> bool {
+        return match self {
+            Error::Connection(_) | Error::Decode(_) | Error::Protocol(_) => true,
+            Error::Remote { status, .. } => *status == FtpStatusCode::Closing,
+            _ => false,
+        }
+    }
+
+    /// The closest `io::ErrorKind`, used to pick the process exit code.
+    pub fn kind(&self) -> ErrorKind {
+        return match self {
+            Error::Io(e) | Error::Connection(e) => e.kind(),
+            Error::Decode(_) | Error::Protocol(_) | Error::ChecksumMismatch => ErrorKind::InvalidData,
+            Error::Remote { status, .. } => match status {
+                FtpStatusCode::NotLoggedIn | FtpStatusCode::PermissionDenied => ErrorKind::PermissionDenied,
+                FtpStatusCode::InvalidPath => ErrorKind::InvalidInput,
+                FtpStatusCode::NotFound => ErrorKind::NotFound,
+                FtpStatusCode::AlreadyExists => ErrorKind::AlreadyExists,
+                FtpStatusCode::InsufficientStorage => ErrorKind::StorageFull,
+                FtpStatusCode::NotSupported => ErrorKind::Unsupported,
+                FtpStatusCode::TransferAborted => ErrorKind::TimedOut,
+                FtpStatusCode::Closing => ErrorKind::ConnectionAborted,
+                FtpStatusCode::Ok | FtpStatusCode::Error => ErrorKind::Other,
+            },
+            Error::InvalidRequest(_) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Connection(e) => write!(f, "Connection lost: {}", e),
+            Error::Decode(e) => write!(f, "Malformed packet: {}", e),
+            Error::Protocol(message) => write!(f, "Protocol error: {}", message),
+            Error::Remote { message, .. } => write!(f, "{}", message),
+            Error::ChecksumMismatch => write!(f, "{}", ERROR_CHECKSUM_MISMATCH),
+            Error::InvalidRequest(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return match self {
+            Error::Io(e) | Error::Connection(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        return Error::Io(e);
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        return Error::Decode(e);
+    }
+}
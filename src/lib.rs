@@ -0,0 +1,12 @@
+#![allow(clippy::needless_return, clippy::module_inception)]
+
+//! File transfer over a TCP control channel and UDP data blocks. `client::connection::Connection`
+//! is the programmatic client, `server::server::Server` the server.
+
+pub mod client;
+pub mod server;
+pub mod core;
+pub mod udp;
+pub mod tcp;
+pub mod error;
+pub mod logging;
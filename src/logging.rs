@@ -0,0 +1,169 @@
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, IsTerminal, Write};
+use std::str::FromStr;
+use chrono::Local;
+use colored::Colorize;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+
+/// Highest `-v` count, each one lowers the default level by one step from `info`.
+pub const MAX_VERBOSITY: u8 = 2;
+/// Bytes of a packet shown in a trace dump, the rest is only counted.
+const MAX_DUMP_BYTES: usize = 256;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human readable line per record, colored on a terminal.
+    #[default]
+    Text,
+    /// One JSON object per line, with `time`, `level`, `target` and `message` keys.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        return match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, format!("Unknown log format {}, expected text or json", s))),
+        }
+    }
+}
+
+/// Which records are logged, parsed from comma separated directives such as
+/// `info,ftp::udp=trace,ftp::server=warn`. A bare level applies to every module, a `module=level`
+/// one to the module and everything below it; the most specific directive wins.
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    default: LevelFilter,
+    /// Module prefixes and their level, most specific first.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// The filter for `-v` given `verbose` times: `info`, then `debug`, then `trace`.
+    pub fn from_verbosity(verbose: u8) -> Self {
+        let default = match verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        return Self { default, modules: vec![] }
+    }
+
+    /// Adds the directives of `spec` on top of this filter.
+    pub fn parse(mut self, spec: &str) -> io::Result<Self> {
+        for directive in spec.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let (module, level) = (module.trim(), parse_level(level)?);
+                    self.modules.retain(|(other, _)| other != module);
+                    self.modules.push((module.to_string(), level));
+                }
+                None => self.default = parse_level(directive)?,
+            }
+        }
+        self.modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(self)
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        return self.modules.iter()
+            .find(|(module, _)| target == module || target.strip_prefix(module.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .map_or(self.default, |(_, level)| *level);
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        return self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max);
+    }
+}
+
+fn parse_level(level: &str) -> io::Result<LevelFilter> {
+    return LevelFilter::from_str(level.trim())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Unknown log level {}, expected off, error, warn, info, debug or trace", level.trim())));
+}
+
+/// Writes the records to the standard error.
+struct Logger {
+    filter: LogFilter,
+    format: LogFormat,
+    color: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        return metadata.level() <= self.filter.level_for(metadata.target());
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z");
+        let line = match self.format {
+            LogFormat::Json => format!(r#"{{"time":"{}","level":"{}","target":"{}","message":"{}"}}"#,
+                time, record.level(), json_escape(record.target()), json_escape(&record.args().to_string())),
+            LogFormat::Text if self.color => format!("{} {} {} {}", time.to_string().dimmed(), colored_level(record.level()), record.target().dimmed(), record.args()),
+            LogFormat::Text => format!("{} {:<5} {} {}", time, record.level(), record.target(), record.args()),
+        };
+        // Logging must never take the program down, a closed standard error loses the record.
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn colored_level(level: Level) -> String {
+    let name = format!("{:<5}", level);
+    return match level {
+        Level::Error => name.red().bold(),
+        Level::Warn => name.yellow().bold(),
+        Level::Info => name.green(),
+        Level::Debug => name.blue(),
+        Level::Trace => name.magenta(),
+    }.to_string();
+}
+
+/// Installs the logger for the whole process, can only be called once.
+pub fn init(filter: LogFilter, format: LogFormat) -> io::Result<()> {
+    log::set_max_level(filter.max_level());
+    let color = format == LogFormat::Text && io::stderr().is_terminal();
+    return log::set_boxed_logger(Box::new(Logger { filter, format, color })).map_err(io::Error::other);
+}
+
+/// Hexadecimal dump of a packet for trace records, cut after `MAX_DUMP_BYTES`.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::with_capacity(bytes.len().min(MAX_DUMP_BYTES) * 3);
+    for (i, byte) in bytes.iter().take(MAX_DUMP_BYTES).enumerate() {
+        if i > 0 {
+            dump.push(' ');
+        }
+        let _ = write!(dump, "{:02x}", byte);
+    }
+    if bytes.len() > MAX_DUMP_BYTES {
+        let _ = write!(dump, " ... ({} more bytes)", bytes.len() - MAX_DUMP_BYTES);
+    }
+    return dump;
+}
+
+/// Escapes `text` for a JSON string literal.
+pub(crate) fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => { let _ = write!(escaped, "\\u{:04x}", c as u32); }
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
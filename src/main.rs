@@ -1,39 +1,196 @@
-extern crate exitcode;
-
-use std::env;
-
-mod client;
-mod server;
-mod core;
-mod udp;
-mod tcp;
-
-use client::client::Client;
-use server::server::Server;
-use crate::core::CoreT;
-
-pub fn print_exception<T>(val: bincode::Result<T>) -> T {
-    return match val {
-        Ok(_) => val.unwrap(),
-        Err(e) => {
-            println!("Error: {}", e);
-            std::process::exit(exitcode::SOFTWARE);
+#![allow(clippy::needless_return)]
+
+use clap::{ArgAction, Parser, Subcommand};
+use colored::Colorize;
+use std::io::ErrorKind;
+
+use ftp::client::client::{read_password, Client};
+use ftp::server::config::{DEFAULT_PORT, ServerConfig};
+use ftp::server::server::Server;
+use ftp::server::users::hash_password;
+use ftp::error::{Error, Result};
+use ftp::core::{CoreT, DEFAULT_BLOCK_SIZE, DEFAULT_WINDOW_SIZE, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+use ftp::logging::{self, LogFilter, LogFormat, MAX_VERBOSITY};
+
+/// File transfer client and server, commands over TCP and file blocks over UDP.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Log more details, -v for transfer retries and timeouts, -vv to dump every packet
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Log levels per module on top of -v, e.g. `ftp::udp=trace,ftp::server=warn`
+    #[arg(long, value_name = "FILTER", global = true)]
+    log: Option<String>,
+    /// Format of the log records written to the standard error: text or json
+    #[arg(long, value_name = "FORMAT", global = true)]
+    log_format: Option<LogFormat>,
+
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Serve a directory to clients, options override the config file
+    Server {
+        /// TOML file holding the server settings
+        #[arg(short, long)]
+        config: Option<String>,
+        /// Address to listen on, keeping the ports of the config file [default: 0.0.0.0]
+        #[arg(short, long)]
+        address: Option<String>,
+        /// TCP port to listen on, keeping the addresses of the config file, the file blocks go over a free UDP port picked per session [default: 22222]
+        #[arg(short, long)]
+        port: Option<u16>,
+        /// Directory served to clients, holding the users' home directories [default: files/]
+        #[arg(short, long)]
+        root: Option<String>,
+        /// User database, one name:hash[:home[:role]] line per user [default: users.txt]
+        #[arg(short, long)]
+        users: Option<String>,
+        /// Failed logins allowed before the connection is closed [default: 3]
+        #[arg(long)]
+        max_login_attempts: Option<u32>,
+        /// Connections served at once [default: 64]
+        #[arg(long)]
+        max_connections: Option<usize>,
+        /// Serve this directory, relative to the root, read-only to clients that don't log in
+        #[arg(long, value_name = "DIRECTORY")]
+        anonymous: Option<String>,
+    },
+    /// Connect to a server and run commands typed on the standard input, or given as a script
+    Client {
+        /// Address of the server
+        #[arg(short, long, default_value = "localhost")]
+        address: String,
+        /// TCP port of the server
+        #[arg(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Block size to request for transfers, the server may lower it
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE as u64, value_parser = clap::value_parser!(u64).range(MIN_BLOCK_SIZE as u64..=MAX_BLOCK_SIZE as u64))]
+        block_size: u64,
+        /// Blocks in flight at once during transfers, the server may lower it
+        #[arg(long, default_value_t = DEFAULT_WINDOW_SIZE, value_parser = clap::value_parser!(u64).range(1..))]
+        window_size: u64,
+        /// Log in as this user, the password is read from FTP_PASSWORD or prompted for
+        #[arg(short, long)]
+        user: Option<String>,
+        /// Run the commands of this file, one per line, then exit
+        #[arg(short, long, value_name = "FILE", conflicts_with = "execute")]
+        batch: Option<String>,
+        /// Run these commands, separated by `;`, then exit
+        #[arg(short, long, value_name = "COMMANDS")]
+        execute: Option<String>,
+        /// Go on with the script after a command failed, the exit code still reports the failure
+        #[arg(short, long)]
+        keep_going: bool,
+    },
+    /// Prompt for a password and print the matching line for the users file
+    HashPassword {
+        name: String,
+    },
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("{} {}", "Error:".red(), e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// Trims the commands and drops the blank ones and the `#` comments.
+fn parse_script(lines: Vec<String>) -> Vec<String> {
+    return lines.iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+}
+
+fn exit_code(error: &Error) -> exitcode::ExitCode {
+    if matches!(error, Error::Decode(_) | Error::Protocol(_)) {
+        return exitcode::PROTOCOL;
+    }
+    return match error.kind() {
+        ErrorKind::NotFound => exitcode::NOINPUT,
+        ErrorKind::PermissionDenied => exitcode::NOPERM,
+        ErrorKind::InvalidInput => exitcode::USAGE,
+        ErrorKind::InvalidData => exitcode::DATAERR,
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset => exitcode::UNAVAILABLE,
+        ErrorKind::AlreadyExists => exitcode::CANTCREAT,
+        ErrorKind::StorageFull => exitcode::IOERR,
+        _ => exitcode::SOFTWARE,
+    }
+}
+
+/// Replaces the address, the port or both of every `address:port` pair in `listen`, keeping the
+/// other part from the config file.
+fn override_listen(listen: &[String], address: Option<&str>, port: Option<u16>) -> Vec<String> {
+    let mut overridden: Vec<String> = vec![];
+    for entry in listen {
+        let (listen_address, listen_port) = entry.rsplit_once(':').unwrap_or((entry, ""));
+        let listen_address = match address {
+            // An IPv6 address needs its brackets to be told apart from the port.
+            Some(address) if address.contains(':') && !address.starts_with('[') => format!("[{}]", address),
+            Some(address) => address.to_string(),
+            None => listen_address.to_string(),
+        };
+        let listen_port = port.map_or(listen_port.to_string(), |port| port.to_string());
+        let entry = format!("{}:{}", listen_address, listen_port);
+        if !overridden.contains(&entry) {
+            overridden.push(entry);
         }
     }
+    return overridden;
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let mut core: Option<Box<dyn CoreT>> = match args[1].as_str() {
-        "-c" => Some(Box::new(Client::new(String::from("localhost"), 22222)?)),
-        "-s" => Some(Box::new(Server::new(String::from("0.0.0.0"), 22222)?)),
-        _ => None,
+fn run(cli: Cli) -> Result<()> {
+    let mut core: Box<dyn CoreT> = match cli.mode {
+        Mode::Server { config, address, port, root, users, max_login_attempts, max_connections, anonymous } => {
+            let mut config = match config {
+                Some(path) => ServerConfig::load(&path)?,
+                None => ServerConfig::default(),
+            };
+            if address.is_some() || port.is_some() {
+                config.listen = override_listen(&config.listen, address.as_deref(), port);
+            }
+            config.root = root.unwrap_or(config.root);
+            config.users_file = users.unwrap_or(config.users_file);
+            config.max_login_attempts = max_login_attempts.unwrap_or(config.max_login_attempts);
+            config.max_connections = max_connections.unwrap_or(config.max_connections);
+            config.anonymous = anonymous.or(config.anonymous);
+            if cli.verbose > 0 {
+                config.verbosity = cli.verbose.min(MAX_VERBOSITY);
+            }
+            config.log_filter = cli.log.or(config.log_filter);
+            config.log_format = cli.log_format.unwrap_or(config.log_format);
+            config.validate()?;
+            logging::init(config.log_filter()?, config.log_format)?;
+            Box::new(Server::new(&config)?)
+        }
+        Mode::Client { address, port, block_size, window_size, user, batch, execute, keep_going } => {
+            let filter = LogFilter::from_verbosity(cli.verbose).parse(cli.log.as_deref().unwrap_or_default())?;
+            logging::init(filter, cli.log_format.unwrap_or_default())?;
+            let script = match (batch, execute) {
+                (Some(path), _) => Some(std::fs::read_to_string(path)?.lines().map(String::from).collect()),
+                (None, Some(commands)) => Some(commands.split(';').map(String::from).collect()),
+                (None, None) => None,
+            };
+            let mut client = Client::new(address, port, block_size as usize, window_size)?;
+            if let Some(user) = user {
+                client.set_username(user);
+            }
+            if let Some(script) = script {
+                client.set_script(parse_script(script), keep_going);
+            }
+            Box::new(client)
+        }
+        Mode::HashPassword { name } => {
+            let password = read_password("Password: ")?;
+            println!("{}:{}", name, hash_password(&password));
+            return Ok(());
+        }
     };
-    if core.is_some() {
-        core.unwrap().run()?;
-    } else {
-        println!("Invalid argument");
-        std::process::exit(exitcode::USAGE);
-    }
-    Ok(())
+    core.run()
 }
@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use crate::logging::json_escape;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditFormat {
+    /// The wu-ftpd `xferlog` line format, readable by the usual FTP log analyzers.
+    #[default]
+    Xferlog,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    /// An upload, `Put`.
+    Incoming,
+    /// A download, `Get`.
+    Outgoing,
+}
+
+/// One `put` or `get`, whether it completed, failed during the data transfer or was refused.
+#[derive(Debug)]
+pub struct TransferRecord<'a> {
+    /// When the transfer ended.
+    pub time: DateTime<Local>,
+    pub duration: Duration,
+    /// IP address of the client.
+    pub peer: &'a str,
+    /// `None` when the client has not logged in.
+    pub user: Option<&'a str>,
+    /// Whether the client is served the anonymous area rather than a home directory.
+    pub anonymous: bool,
+    /// Path of the file, relative to the served directory and starting with `/`. A path refused
+    /// before being resolved keeps its `..` components.
+    pub path: &'a str,
+    /// Bytes sent or received during this transfer, a resumed prefix not included, 0 when refused.
+    pub bytes: u64,
+    pub direction: Direction,
+    /// Why the transfer failed, `None` when it completed.
+    pub error: Option<String>,
+}
+
+/// Append-only record of the transfers, shared by every connection.
+pub struct AuditLog {
+    file: Mutex<File>,
+    format: AuditFormat,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &str, format: AuditFormat) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not open audit log {}: {}", path, e)))?;
+        return Ok(Self { file: Mutex::new(file), format });
+    }
+
+    pub fn record(&self, record: &TransferRecord) -> std::io::Result<()> {
+        let line = match self.format {
+            AuditFormat::Xferlog => xferlog_line(record),
+            AuditFormat::Json => json_line(record),
+        };
+        // A single write per record so that the lines of concurrent sessions never interleave.
+        let mut file = self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        return file.write_all(line.as_bytes());
+    }
+}
+
+/// `current-time transfer-time remote-host file-size filename transfer-type special-action-flag
+/// direction access-mode username service-name authentication-method authenticated-user-id
+/// completion-status`, blanks in the file name are replaced by `_` to keep the fields apart and a
+/// client that has not logged in is named `*`.
+fn xferlog_line(record: &TransferRecord) -> String {
+    let path: String = record.path.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect();
+    return format!("{} {} {} {} {} b _ {} {} {} ftp 0 * {}\n",
+        record.time.format("%a %b %e %H:%M:%S %Y"),
+        record.duration.as_secs_f64().round() as u64,
+        record.peer,
+        record.bytes,
+        path,
+        match record.direction { Direction::Incoming => 'i', Direction::Outgoing => 'o' },
+        if record.anonymous { 'a' } else { 'r' },
+        record.user.unwrap_or("*"),
+        if record.error.is_none() { 'c' } else { 'i' });
+}
+
+fn json_line(record: &TransferRecord) -> String {
+    let error = match &record.error {
+        Some(error) => format!(r#""{}""#, json_escape(error)),
+        None => "null".to_string(),
+    };
+    let user = match record.user {
+        Some(user) => format!(r#""{}""#, json_escape(user)),
+        None => "null".to_string(),
+    };
+    return format!(r#"{{"time":"{}","duration_ms":{},"peer":"{}","user":{},"anonymous":{},"path":"{}","bytes":{},"direction":"{}","status":"{}","error":{}}}"#,
+        record.time.to_rfc3339(),
+        record.duration.as_millis(),
+        json_escape(record.peer),
+        user,
+        record.anonymous,
+        json_escape(record.path),
+        record.bytes,
+        match record.direction { Direction::Incoming => "incoming", Direction::Outgoing => "outgoing" },
+        if record.error.is_none() { "complete" } else { "failed" },
+        error) + "\n";
+}
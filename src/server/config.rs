@@ -0,0 +1,144 @@
+use std::io::{Error, ErrorKind};
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::core::{MAX_BLOCK_SIZE, MAX_WINDOW_BYTES, MIN_BLOCK_SIZE, TransferConfig};
+use crate::logging::{LogFilter, LogFormat, MAX_VERBOSITY};
+use crate::server::audit::AuditFormat;
+use crate::server::server::{MAX_LOGIN_ATTEMPTS, ROOT_DIRECTORY, USERS_FILE};
+
+/// Default port of the control channel, the data channel of each session gets a free UDP port.
+pub const DEFAULT_PORT: u16 = 22222;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// Server settings, read from a TOML file where every key is optional and then overridden by the
+/// command line. `validate` must pass before the config is handed to `Server::new`.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `address:port` pairs to accept connections on.
+    pub listen: Vec<String>,
+    /// Directory served to clients, holding the users' home directories.
+    pub root: String,
+    /// User database, see `UserDatabase`.
+    pub users_file: String,
+    /// Directory inside `root` served read-only to clients that don't log in.
+    pub anonymous: Option<String>,
+    pub max_login_attempts: u32,
+    /// Connections served at once, the next ones are turned away.
+    pub max_connections: usize,
+    /// Largest block size granted to clients.
+    pub max_block_size: usize,
+    /// Largest number of blocks in flight at once granted to clients.
+    pub window_size: u64,
+    /// Milliseconds before an unacknowledged block is sent again, clients are told to use it too.
+    pub ack_timeout_ms: u64,
+    /// Times a block is sent before the transfer is aborted, clients are told to use it too.
+    pub max_retries: u32,
+    /// 0 logs from `info` on, 1 from `debug` on with transfer retries and timeouts, 2 dumps every
+    /// packet.
+    pub verbosity: u8,
+    /// Directives refining `verbosity` for some modules, e.g. `ftp::udp=trace`, see `LogFilter`.
+    pub log_filter: Option<String>,
+    pub log_format: LogFormat,
+    /// File every `put` and `get` is appended to, auditing is disabled when `None`.
+    pub audit_log: Option<String>,
+    pub audit_format: AuditFormat,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let transfer = TransferConfig::default();
+        return Self {
+            listen: vec![format!("0.0.0.0:{}", DEFAULT_PORT)],
+            root: ROOT_DIRECTORY.to_string(),
+            users_file: USERS_FILE.to_string(),
+            anonymous: None,
+            max_login_attempts: MAX_LOGIN_ATTEMPTS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_block_size: MAX_BLOCK_SIZE,
+            window_size: transfer.window_size,
+            ack_timeout_ms: transfer.ack_timeout.as_millis() as u64,
+            max_retries: transfer.max_retries,
+            verbosity: 0,
+            log_filter: None,
+            log_format: LogFormat::Text,
+            audit_log: None,
+            audit_format: AuditFormat::Xferlog,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not read config file {}: {}", path, e)))?;
+        return toml::from_str(&content)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid config file {}: {}", path, e)));
+    }
+
+    /// Checks every setting, the error names the offending key.
+    pub fn validate(&self) -> std::io::Result<()> {
+        if self.listen.is_empty() {
+            return Err(invalid("listen", "at least one address is required"));
+        }
+        for address in &self.listen {
+            address.to_socket_addrs().map_err(|e| invalid("listen", &format!("{}: {}", address, e)))?;
+        }
+        if !Path::new(&self.root).is_dir() {
+            return Err(invalid("root", &format!("{} is not a directory", self.root)));
+        }
+        if !Path::new(&self.users_file).is_file() {
+            return Err(invalid("users_file", &format!("{} is not a file", self.users_file)));
+        }
+        if self.max_login_attempts == 0 {
+            return Err(invalid("max_login_attempts", "must be at least 1"));
+        }
+        if self.max_connections == 0 {
+            return Err(invalid("max_connections", "must be at least 1"));
+        }
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.max_block_size) {
+            return Err(invalid("max_block_size", &format!("must be between {} and {}", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)));
+        }
+        if self.window_size == 0 {
+            return Err(invalid("window_size", "must be at least 1"));
+        }
+        if self.window_size.saturating_mul(self.max_block_size as u64) > MAX_WINDOW_BYTES {
+            return Err(invalid("window_size", &format!("must be at most {} with a max_block_size of {}", MAX_WINDOW_BYTES / self.max_block_size as u64, self.max_block_size)));
+        }
+        if self.ack_timeout_ms == 0 {
+            return Err(invalid("ack_timeout_ms", "must be at least 1"));
+        }
+        if self.max_retries == 0 {
+            return Err(invalid("max_retries", "must be at least 1"));
+        }
+        if self.verbosity > MAX_VERBOSITY {
+            return Err(invalid("verbosity", &format!("must be between 0 and {}", MAX_VERBOSITY)));
+        }
+        self.log_filter()?;
+        Ok(())
+    }
+
+    pub fn log_filter(&self) -> Result<LogFilter, Error> {
+        let filter = LogFilter::from_verbosity(self.verbosity);
+        return match &self.log_filter {
+            Some(spec) => filter.parse(spec).map_err(|e| invalid("log_filter", &e.to_string())),
+            None => Ok(filter),
+        }
+    }
+
+    /// Transfer settings before the block size is negotiated.
+    pub fn transfer_config(&self) -> TransferConfig {
+        return TransferConfig {
+            window_size: self.window_size,
+            ack_timeout: Duration::from_millis(self.ack_timeout_ms),
+            max_retries: self.max_retries,
+            ..TransferConfig::default()
+        }
+    }
+}
+
+fn invalid(key: &str, message: &str) -> Error {
+    return Error::new(ErrorKind::InvalidInput, format!("Invalid config value for {}: {}", key, message));
+}
@@ -1 +1,5 @@
-pub mod server;
\ No newline at end of file
+pub mod server;
+pub mod config;
+pub mod path;
+pub mod users;
+pub mod audit;
@@ -0,0 +1,209 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use crate::core::{ERROR_NAME_TOO_LONG, ERROR_PATH_TOO_LONG, MAX_NAME_LENGTH, MAX_PATH_LENGTH};
+
+pub static ERROR_PATH_ESCAPES_ROOT: &str = "Path leads outside of the home directory";
+pub static ERROR_INVALID_CHARACTER: &str = "Path contains a control character";
+
+/// Maps a path sent by a client onto `root`, which must already be canonical. A relative path
+/// starts from `cwd`, itself relative to `root`, and an absolute one from `root`.
+///
+/// The client path must be at most `MAX_PATH_LENGTH` bytes with components of at most
+/// `MAX_NAME_LENGTH` bytes, and free of control characters.
+///
+/// The client path is normalised lexically (`.` is dropped, `..` pops a component) and must stay
+/// inside `root` at every step. The part of the result that already exists on disk is then
+/// resolved through its symlinks and must still be inside `root`, and a dangling symlink is
+/// refused since creating a file through it could land anywhere. The returned path is not
+/// canonical so that the last component can itself be a symlink, e.g. to delete or rename it.
+pub fn resolve_path(root: &Path, cwd: &Path, client_path: &str) -> std::io::Result<PathBuf> {
+    if client_path.len() > MAX_PATH_LENGTH {
+        return Err(Error::new(ErrorKind::InvalidInput, ERROR_PATH_TOO_LONG));
+    }
+    if client_path.chars().any(char::is_control) {
+        return Err(Error::new(ErrorKind::InvalidInput, ERROR_INVALID_CHARACTER));
+    }
+
+    let mut relative = cwd.to_path_buf();
+    for component in Path::new(client_path).components() {
+        match component {
+            Component::Normal(name) if name.len() > MAX_NAME_LENGTH => {
+                return Err(Error::new(ErrorKind::InvalidInput, ERROR_NAME_TOO_LONG));
+            }
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(Error::new(ErrorKind::PermissionDenied, ERROR_PATH_ESCAPES_ROOT));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => relative.clear(),
+        }
+    }
+
+    let path = root.join(relative);
+    let mut existing = path.as_path();
+    loop {
+        match existing.canonicalize() {
+            Ok(real_path) => {
+                if !real_path.starts_with(root) {
+                    return Err(Error::new(ErrorKind::PermissionDenied, ERROR_PATH_ESCAPES_ROOT));
+                }
+                return Ok(path);
+            }
+            Err(e) => match existing.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return Err(Error::new(ErrorKind::PermissionDenied, ERROR_PATH_ESCAPES_ROOT));
+                }
+                Ok(_) => return Err(e),
+                Err(_) => {
+                    existing = match existing.parent() {
+                        Some(parent) if parent.starts_with(root) => parent,
+                        _ => return Err(e),
+                    };
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    /// A canonical directory under the system temporary directory, removed with everything in it
+    /// when dropped.
+    struct TempRoot {
+        path: PathBuf,
+    }
+
+    impl TempRoot {
+        fn new() -> Self {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!("ftp-path-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst));
+            let path = std::env::temp_dir().join(name);
+            fs::create_dir_all(path.join("root/dir")).unwrap();
+            fs::create_dir_all(path.join("outside")).unwrap();
+            fs::write(path.join("outside/secret"), "secret").unwrap();
+            return Self { path: path.canonicalize().unwrap() }
+        }
+
+        fn root(&self) -> PathBuf {
+            return self.path.join("root");
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn assert_error(result: std::io::Result<PathBuf>, kind: ErrorKind, message: &str) {
+        let error = result.expect_err("the path should have been refused");
+        assert_eq!(error.kind(), kind);
+        assert_eq!(error.to_string(), message);
+    }
+
+    fn assert_escapes(root: &Path, cwd: &str, client_path: &str) {
+        assert_error(resolve_path(root, Path::new(cwd), client_path), ErrorKind::PermissionDenied, ERROR_PATH_ESCAPES_ROOT);
+    }
+
+    #[test]
+    fn parent_dir_cannot_leave_root() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        assert_escapes(&root, "", "..");
+        assert_escapes(&root, "", "../../etc/passwd");
+        assert_escapes(&root, "", "a/../../x");
+        assert_escapes(&root, "", "/../x");
+        assert_escapes(&root, "", "dir/./../..");
+    }
+
+    #[test]
+    fn parent_dir_cannot_leave_root_from_cwd() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        assert_escapes(&root, "dir", "../..");
+        assert_escapes(&root, "dir", "../../outside/secret");
+        assert_escapes(&root, "dir", "sub/../../../x");
+        assert_eq!(resolve_path(&root, Path::new("dir"), "../file").unwrap(), root.join("file"));
+    }
+
+    #[test]
+    fn absolute_paths_start_from_root() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        assert_eq!(resolve_path(&root, Path::new("dir"), "/etc/passwd").unwrap(), root.join("etc/passwd"));
+        assert_eq!(resolve_path(&root, Path::new("dir"), "/").unwrap(), root);
+        assert_eq!(resolve_path(&root, Path::new(""), "//dir//./file").unwrap(), root.join("dir/file"));
+    }
+
+    #[test]
+    fn symlink_out_of_root_is_refused() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        symlink(temp.path.join("outside"), root.join("escape")).unwrap();
+        symlink(temp.path.join("outside/secret"), root.join("dir/secret")).unwrap();
+        assert_escapes(&root, "", "escape");
+        assert_escapes(&root, "", "escape/secret");
+        assert_escapes(&root, "", "escape/new_file");
+        assert_escapes(&root, "dir", "secret");
+    }
+
+    #[test]
+    fn dangling_symlink_is_refused() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        symlink(temp.path.join("outside/missing"), root.join("dangling")).unwrap();
+        symlink("missing", root.join("dir/dangling")).unwrap();
+        assert_escapes(&root, "", "dangling");
+        assert_escapes(&root, "", "dangling/new_file");
+        assert_escapes(&root, "dir", "dangling");
+    }
+
+    #[test]
+    fn symlink_inside_root_is_accepted() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        fs::write(root.join("dir/file"), "file").unwrap();
+        symlink("dir", root.join("link")).unwrap();
+        symlink(root.join("dir/file"), root.join("file_link")).unwrap();
+        assert_eq!(resolve_path(&root, Path::new(""), "link/file").unwrap(), root.join("link/file"));
+        assert_eq!(resolve_path(&root, Path::new(""), "link/new_file").unwrap(), root.join("link/new_file"));
+        assert_eq!(resolve_path(&root, Path::new("link"), "../file_link").unwrap(), root.join("file_link"));
+    }
+
+    #[test]
+    fn control_characters_are_refused() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        for client_path in ["a\nb", "a\0b", "dir/\rfile", "\x1b[2Jfile", "file\u{7f}", "file\u{85}"] {
+            assert_error(resolve_path(&root, Path::new(""), client_path), ErrorKind::InvalidInput, ERROR_INVALID_CHARACTER);
+        }
+    }
+
+    #[test]
+    fn long_names_are_refused() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        let longest_name = "a".repeat(MAX_NAME_LENGTH);
+        assert_eq!(resolve_path(&root, Path::new(""), &longest_name).unwrap(), root.join(&longest_name));
+        assert_error(resolve_path(&root, Path::new(""), &"a".repeat(MAX_NAME_LENGTH + 1)), ErrorKind::InvalidInput, ERROR_NAME_TOO_LONG);
+        assert_error(resolve_path(&root, Path::new(""), &format!("dir/{}/file", "é".repeat(MAX_NAME_LENGTH / 2 + 1))), ErrorKind::InvalidInput, ERROR_NAME_TOO_LONG);
+    }
+
+    #[test]
+    fn long_paths_are_refused() {
+        let temp = TempRoot::new();
+        let root = temp.root();
+        let longest_path = "a/".repeat(MAX_PATH_LENGTH / 2);
+        assert_eq!(longest_path.len(), MAX_PATH_LENGTH);
+        assert!(resolve_path(&root, Path::new(""), &longest_path).is_ok());
+        assert_error(resolve_path(&root, Path::new(""), &(longest_path + "a")), ErrorKind::InvalidInput, ERROR_PATH_TOO_LONG);
+        assert_error(resolve_path(&root, Path::new(""), &"./".repeat(MAX_PATH_LENGTH)), ErrorKind::InvalidInput, ERROR_PATH_TOO_LONG);
+    }
+}
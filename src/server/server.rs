@@ -1,138 +1,625 @@
-use std::fmt::format;
-use std::io::Error;
-use crate::core::{CommandId, CoreT, ERROR_FAILED_TO_CREATE_FILE, FtpStatusCode, receive_file, send_file};
-use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
-use std::{thread, time};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use crate::core::{accept_resume, Capabilities, CommandId, CoreT, create_file, EntryKind, ERROR_RESUME_NOT_SUPPORTED, FtpStatusCode, io_error_message, LIST_CHUNK_SIZE, negotiate_block_size, negotiate_window_size, open_partial_file, propose_resume, receive_file, send_file, TransferConfig};
+use std::io::ErrorKind;
+use crate::error::{Error, Result};
+use std::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::Local;
+use std::fs::{File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
 use crate::tcp::tcp::{Tcp};
-use colored::*;
-use crate::tcp::packet::{CommandPacket, deserialize, FilePacket, FileInfoPacket, ResponseFilePacket, ResponsePacket, UdpConfigPacket};
+use log::{debug, error, info, warn};
+use crate::tcp::packet::{CommandPacket, FileInfoPacket, FileStatPacket, HelloPacket, ListEntry, ListPacket, LoginPacket, RenamePacket, ResponsePacket, UdpConfigPacket};
 use crate::udp::udp::{Udp};
-use std::str;
+use crate::server::audit::{AuditLog, Direction, TransferRecord};
+use crate::server::config::ServerConfig;
+use crate::server::path::resolve_path;
+use crate::server::users::{Permission, Role, UserDatabase};
+
+/// Default directory served to clients, each user is confined to its home directory inside of it.
+pub const ROOT_DIRECTORY: &str = "files/";
+
+/// Default file the user database is loaded from, see `UserDatabase`.
+pub const USERS_FILE: &str = "users.txt";
+/// Names that log into the anonymous area with any password, when it is enabled.
+pub const ANONYMOUS_USERS: [&str; 2] = ["anonymous", "ftp"];
+/// Failed logins allowed on a connection before the server closes it.
+pub const MAX_LOGIN_ATTEMPTS: u32 = 3;
+
+pub static ERROR_ROOT_DIRECTORY: &str = "The home directory itself cannot be modified";
+pub static ERROR_NOT_A_DIRECTORY: &str = "Not a directory";
+pub static ERROR_NOT_LOGGED_IN: &str = "Please login first";
+pub static ERROR_PERMISSION_DENIED: &str = "Permission denied";
+pub static ERROR_LOGIN_INCORRECT: &str = "Login incorrect";
+pub static ERROR_TOO_MANY_LOGIN_ATTEMPTS: &str = "Too many failed login attempts, closing connection";
+pub static ERROR_TOO_MANY_CONNECTIONS: &str = "Too many connections, try again later";
 
 pub struct Server {
-    listener: TcpListener
+    listeners: Vec<TcpListener>,
+    state: Arc<ServerState>,
+}
+
+/// Everything the connection threads share.
+struct ServerState {
+    /// Canonical path of the served directory.
+    root: PathBuf,
+    users: UserDatabase,
+    /// Canonical public directory served read-only to clients that don't log in, `None` when
+    /// anonymous access is disabled.
+    anonymous_root: Option<PathBuf>,
+    max_login_attempts: u32,
+    max_connections: usize,
+    /// Connections currently served.
+    connections: AtomicUsize,
+    max_block_size: usize,
+    /// Transfer settings, the block size is negotiated for each connection.
+    transfer_config: TransferConfig,
+    /// Where `put` and `get` are recorded, `None` when auditing is disabled.
+    audit_log: Option<AuditLog>,
+}
+
+/// State of a single client connection.
+struct Session {
+    /// Name of the authenticated user, `None` until a successful `Login`.
+    user: Option<String>,
+    role: Role,
+    failed_logins: u32,
+    /// Canonical home directory of the user, client paths can't lead outside of it.
+    root: PathBuf,
+    /// Current directory, relative to `root`.
+    cwd: PathBuf,
+    /// Features both sides support, see `HelloPacket`.
+    capabilities: Capabilities,
+    /// Whether the session is served the anonymous area rather than a home directory.
+    anonymous: bool,
 }
 
-trait ServerT {}
+impl Session {
+    fn start_anonymous(&mut self, anonymous_root: &Path) {
+        self.user = Some(ANONYMOUS_USERS[0].to_string());
+        self.anonymous = true;
+        self.role = Role::ReadOnly;
+        self.root = anonymous_root.to_path_buf();
+        self.cwd = PathBuf::new();
+    }
+}
 
 impl CoreT for Server {
-    fn run(&mut self) -> std::io::Result<()> {
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("{} {}", "New connection: ".bold(), stream.peer_addr().unwrap().to_string().underline());
-                    thread::spawn(move|| -> std::io::Result<()> {
-                        handle_client(Tcp { stream });
-                        Ok(())
-                    });
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                }
+    fn run(&mut self) -> Result<()> {
+        thread::scope(|scope| {
+            for listener in &self.listeners {
+                scope.spawn(|| accept_connections(listener, &self.state));
             }
-        }
+        });
         Ok(())
     }
 }
 
-impl ServerT for Server {}
-
 impl Server {
-    pub fn new(ip: String, port: u16) -> std::io::Result<Self> {
-        let address = format!("{}:{}", ip, port);
-        let listener = TcpListener::bind(address.clone())?;
-        println!("{} {address}", "Server is running and listen at address".green().bold());
-        Ok(Server { listener })
+    pub fn new(config: &ServerConfig) -> Result<Self> {
+        let root = std::fs::canonicalize(&config.root)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("Could not open served directory {}: {}", config.root, e)))?;
+        let users = UserDatabase::load(&config.users_file)?;
+        info!("Loaded {} users from {}", users.user_count(), config.users_file);
+        let anonymous_root = match &config.anonymous {
+            Some(directory) => {
+                let anonymous_root = open_home(&root, directory)?;
+                info!("Anonymous access to {}", anonymous_root.display());
+                Some(anonymous_root)
+            }
+            None => None,
+        };
+        let audit_log = match &config.audit_log {
+            Some(path) => {
+                info!("Recording transfers to {}", path);
+                Some(AuditLog::open(path, config.audit_format)?)
+            }
+            None => None,
+        };
+        let mut listeners = vec![];
+        for address in &config.listen {
+            listeners.push(TcpListener::bind(address)?);
+            info!("Listening on {address}");
+        }
+        let state = ServerState {
+            root,
+            users,
+            anonymous_root,
+            max_login_attempts: config.max_login_attempts,
+            max_connections: config.max_connections,
+            connections: AtomicUsize::new(0),
+            max_block_size: config.max_block_size,
+            transfer_config: config.transfer_config(),
+            audit_log,
+        };
+        Ok(Server { listeners, state: Arc::new(state) })
+    }
+}
+
+fn accept_connections(listener: &TcpListener, state: &Arc<ServerState>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let tcp = Tcp { stream };
+                let peer = tcp.peer_addr_to_string();
+                info!("{} New connection", peer);
+                let state = Arc::clone(state);
+                thread::spawn(move|| {
+                    let accepted = state.connections.fetch_add(1, Ordering::SeqCst) < state.max_connections;
+                    let result = if accepted { handle_client(tcp, &state) } else { refuse_client(tcp) };
+                    state.connections.fetch_sub(1, Ordering::SeqCst);
+                    if let Err(e) = result {
+                        warn!("{} Session ended: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Could not accept a connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Answers the first command of a client the server has no room for, then closes the connection.
+fn refuse_client(mut tcp: Tcp) -> Result<()> {
+    warn!("{} Refused, too many connections", tcp.peer_addr_to_string());
+    tcp.read::<HelloPacket>()?;
+    tcp.write(&HelloPacket::default())?;
+    let requested = tcp.read::<UdpConfigPacket>()?;
+    let socket = UdpSocket::bind((tcp.stream.local_addr()?.ip(), 0))?;
+    tcp.write(&UdpConfigPacket { port: socket.local_addr()?.port(), ..requested })?;
+    let command = tcp.read::<CommandPacket>()?;
+    if !matches!(command.cmd, CommandId::Exit) {
+        tcp.read_raw()?;
+        tcp.write(&ResponsePacket::new(FtpStatusCode::Closing, ERROR_TOO_MANY_CONNECTIONS))?;
     }
+    return exit(&mut tcp);
 }
 
-pub const FILE_BLOC_SIZE: usize = 1024;
+fn handle_client(mut tcp: Tcp, state: &ServerState) -> Result<()> {
+    // Answer with our own hello even to a client we can't talk to, so that it can tell why.
+    let hello = tcp.read::<HelloPacket>()?;
+    tcp.write(&HelloPacket::default())?;
+    hello.check_version()?;
+    let capabilities = hello.shared_capabilities();
+
+    let requested = tcp.read::<UdpConfigPacket>()?;
+    // A socket of its own on a free port, so that sessions never receive each other's blocks.
+    let socket = UdpSocket::bind((tcp.stream.local_addr()?.ip(), 0))?;
+    socket.connect((tcp.stream.peer_addr()?.ip(), requested.port))?;
+    let mut udp = Udp::new(socket);
+    let transfer_config = TransferConfig {
+        block_size: negotiate_block_size(requested.packet_size, state.max_block_size),
+        window_size: negotiate_window_size(requested.window_size, state.transfer_config.window_size),
+        checksum: capabilities.contains(Capabilities::CHECKSUM),
+        ..state.transfer_config
+    };
+    tcp.write(&UdpConfigPacket::new(&transfer_config, udp.socket.local_addr()?.port()))?;
+    let mut session = Session { user: None, role: Role::ReadOnly, failed_logins: 0, root: PathBuf::new(), cwd: PathBuf::new(), capabilities, anonymous: false };
+    if let Some(anonymous_root) = &state.anonymous_root {
+        session.start_anonymous(anonymous_root);
+    }
 
-fn handle_client(mut tcp: Tcp) -> std::io::Result<()> {
-    let mut socket = UdpSocket::bind(tcp.stream.local_addr().unwrap()).expect("Could not bind an UDP socket");
-    socket.connect(tcp.peer_addr_to_string()).expect("Could not connect to server");
-    let mut udp = Udp { socket };
     loop {
-        println!("{} {}", format!("{}", tcp.peer_addr_to_string().underline()).bold(), "wait for command".truecolor(252, 190, 3).bold());
-        let command = tcp.read::<CommandPacket>();
-        match command.cmd {
-            CommandId::Put => {
-                put(&mut udp, & mut tcp);
-            }
-            CommandId::Get => {
-                get(&mut udp, & mut tcp);
+        debug!("{} Waiting for a command", tcp.peer_addr_to_string());
+        let command = tcp.read::<CommandPacket>()?;
+        let refusal = match required_permission(&command.cmd) {
+            Some(_) if session.user.is_none() => Some((FtpStatusCode::NotLoggedIn, ERROR_NOT_LOGGED_IN)),
+            Some(permission) if !session.role.allows(permission) => Some((FtpStatusCode::PermissionDenied, ERROR_PERMISSION_DENIED)),
+            _ => None,
+        };
+        if let Some((status, message)) = refusal {
+            // Every command needing a permission sends exactly one request packet, only a transfer's
+            // is looked at, for the audit log.
+            let request = tcp.read_raw()?;
+            warn!("{} Refused command {:?}: {}", tcp.peer_addr_to_string(), command.cmd, message);
+            if let Some(direction) = transfer_direction(&command.cmd) {
+                let name = bincode::deserialize::<FileInfoPacket>(&request).map_or_else(|_| String::new(), |packet| packet.name);
+                audit_refusal(&tcp, state, &session, &requested_path(state, &session, &name), direction, message);
             }
+            tcp.write(&ResponsePacket::new(status, message))?;
+            continue;
+        }
+        let result = match command.cmd {
+            CommandId::Login => login(&mut tcp, state, &mut session),
+            CommandId::ChangeDir => change_dir(&mut tcp, &mut session),
+            CommandId::Put => put(&mut udp, & mut tcp, state, &session, &transfer_config),
+            CommandId::Get => get(&mut udp, & mut tcp, state, &session, &transfer_config),
+            CommandId::List => list(&mut tcp, &session),
+            CommandId::MakeDir => make_dir(&mut tcp, &session),
+            CommandId::RemoveDir => remove_dir(&mut tcp, &session),
+            CommandId::Delete => delete(&mut tcp, &session),
+            CommandId::Rename => rename(&mut tcp, &session),
             CommandId::Exit => {
-                exit(&mut udp, & mut tcp);
-                return Ok(());
+                return exit(&mut tcp);
+            }
+        };
+        match result {
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => warn!("{} {:?} failed: {}", tcp.peer_addr_to_string(), command.cmd, e),
+            Ok(()) => {}
+        }
+        if session.failed_logins >= state.max_login_attempts {
+            return exit(&mut tcp);
+        }
+    }
+}
+
+/// Which way a transfer command moves a file, `None` when `cmd` is not a transfer.
+fn transfer_direction(cmd: &CommandId) -> Option<Direction> {
+    return match cmd {
+        CommandId::Put => Some(Direction::Incoming),
+        CommandId::Get => Some(Direction::Outgoing),
+        _ => None,
+    }
+}
+
+/// What the session's role must allow to run `cmd`, `None` when the command is always available.
+fn required_permission(cmd: &CommandId) -> Option<Permission> {
+    return match cmd {
+        CommandId::Exit | CommandId::Login => None,
+        CommandId::Get => Some(Permission::Read),
+        CommandId::List | CommandId::ChangeDir => Some(Permission::List),
+        CommandId::Put | CommandId::MakeDir => Some(Permission::Write),
+        CommandId::Rename => Some(Permission::Rename),
+        CommandId::Delete | CommandId::RemoveDir => Some(Permission::Delete),
+    }
+}
+
+fn login(tcp: &mut Tcp, state: &ServerState, session: &mut Session) -> Result<()> {
+    let packet = tcp.read::<LoginPacket>()?;
+    if let Some(anonymous_root) = &state.anonymous_root {
+        if ANONYMOUS_USERS.contains(&packet.username.as_str()) {
+            info!("{} Logged in anonymously", tcp.peer_addr_to_string());
+            session.start_anonymous(anonymous_root);
+            tcp.write(&ResponsePacket::ok())?;
+            return Ok(());
+        }
+    }
+    match state.users.authenticate(&packet.username, &packet.password) {
+        Some(user) => {
+            let root = match open_home(&state.root, &user.home) {
+                Ok(root) => root,
+                Err(e) => {
+                    error!("{} No home directory for {}: {}", tcp.peer_addr_to_string(), user.name, e);
+                    tcp.write(&ResponsePacket::new(FtpStatusCode::from_io_error(&e), &io_error_message(&e)))?;
+                    return Ok(());
+                }
+            };
+            info!("{} Logged in as {}", tcp.peer_addr_to_string(), user.name);
+            session.user = Some(user.name.clone());
+            session.anonymous = false;
+            session.role = user.role;
+            session.failed_logins = 0;
+            session.root = root;
+            session.cwd = PathBuf::new();
+            tcp.write(&ResponsePacket::ok())?;
+        }
+        None => {
+            warn!("{} Failed login for {}", tcp.peer_addr_to_string(), packet.username);
+            match &state.anonymous_root {
+                Some(anonymous_root) => session.start_anonymous(anonymous_root),
+                None => {
+                    session.user = None;
+                    session.anonymous = false;
+                }
             }
-            _ => {
-                println!("{} {}", "Unknown command from".red(), tcp.peer_addr_to_string().underline());
+            session.failed_logins += 1;
+            if session.failed_logins >= state.max_login_attempts {
+                tcp.write(&ResponsePacket::new(FtpStatusCode::Closing, ERROR_TOO_MANY_LOGIN_ATTEMPTS))?;
+            } else {
+                tcp.write(&ResponsePacket::new(FtpStatusCode::NotLoggedIn, ERROR_LOGIN_INCORRECT))?;
             }
         }
     }
+    Ok(())
+}
+
+/// Returns the canonical path of a home directory, creating it on first login.
+fn open_home(root: &Path, home: &str) -> std::io::Result<PathBuf> {
+    let path = resolve_path(root, Path::new(""), home)?;
+    std::fs::create_dir_all(&path)?;
+    return std::fs::canonicalize(path);
+}
+
+/// Moves into a directory and answers with the new current directory, `cd .` is how the client
+/// asks for it.
+fn change_dir(tcp: &mut Tcp, session: &mut Session) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let path = match resolve_or_reply(tcp, session, &packet.name)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if !path.is_dir() {
+        tcp.write(&ResponsePacket::new(FtpStatusCode::InvalidPath, ERROR_NOT_A_DIRECTORY))?;
+        return Ok(());
+    }
+    session.cwd = path.strip_prefix(&session.root).unwrap_or(Path::new("")).to_path_buf();
+    tcp.write(&ResponsePacket::new(FtpStatusCode::Ok, &Path::new("/").join(&session.cwd).to_string_lossy()))?;
+    Ok(())
 }
 
-fn put(udp: &mut Udp, tcp: &mut Tcp) -> std::io::Result<()> {
-    let packet = tcp.read::<FileInfoPacket>();
-    let path = get_path_from_filename(&packet.name)?;
+fn put(udp: &mut Udp, tcp: &mut Tcp, state: &ServerState, session: &Session, transfer_config: &TransferConfig) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let refuse = |tcp: &mut Tcp, path: &str, status, message: &str| refuse_transfer(tcp, state, session, path, Direction::Incoming, status, message);
+    if packet.resume && !session.capabilities.contains(Capabilities::RESUME) {
+        return refuse(tcp, &requested_path(state, session, &packet.name), FtpStatusCode::NotSupported, ERROR_RESUME_NOT_SUPPORTED);
+    }
+    // Resuming compares against the partial file, which would leak its content to a drop box.
+    if packet.resume && !session.role.allows(Permission::Read) {
+        return refuse(tcp, &requested_path(state, session, &packet.name), FtpStatusCode::PermissionDenied, ERROR_PERMISSION_DENIED);
+    }
+    let path = match resolve_path(&session.root, &session.cwd, &packet.name) {
+        Ok(path) if path == session.root => return refuse(tcp, &requested_path(state, session, &packet.name), FtpStatusCode::InvalidPath, ERROR_ROOT_DIRECTORY),
+        Ok(path) => path,
+        Err(e) => return refuse(tcp, &requested_path(state, session, &packet.name), FtpStatusCode::InvalidPath, &e.to_string()),
+    };
 
-    let mut file = match std::fs::File::create(path) {
+    let file = if packet.resume { open_partial_file(&path) } else { create_file(&path) };
+    let mut file = match file {
         Ok(file) => file,
-        Err(e) => {
-            let mut error_packet = ResponsePacket { status: FtpStatusCode::Error, message: [0; 150] };
-            error_packet.message[..ERROR_FAILED_TO_CREATE_FILE.len()].copy_from_slice(ERROR_FAILED_TO_CREATE_FILE.as_bytes());
-            tcp.write(&error_packet);
-            return Ok(())
+        Err(e) => return refuse(tcp, &served_path(state, &path), FtpStatusCode::from_io_error(&e), &io_error_message(&e)),
+    };
+    tcp.write(&ResponsePacket::ok())?;
+
+    let res = tcp.read::<ResponsePacket>()?;
+    if res.status != FtpStatusCode::Ok {
+        warn!("{} Client refused the upload: {}", tcp.peer_addr_to_string(), res.message());
+        audit_refusal(tcp, state, session, &served_path(state, &path), Direction::Incoming, &res.message());
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    let mut transferred = 0;
+    let start_index = if packet.resume { propose_resume(&file, tcp, transfer_config) } else { Ok(0) };
+    let result = start_index.and_then(|start_index| {
+        let offset = start_index * transfer_config.block_size as u64;
+        receive_file(&mut file, udp, tcp, transfer_config, start_index, packet.size, &mut |progress| transferred = progress.transferred.saturating_sub(offset))
+    });
+    audit(tcp, state, session, &path, Direction::Incoming, started, transferred, &result);
+    if matches!(result, Err(Error::ChecksumMismatch)) {
+        std::fs::remove_file(&path)?;
+    }
+    return result;
+}
+
+fn get(udp: &mut Udp, tcp: &mut Tcp, state: &ServerState, session: &Session, transfer_config: &TransferConfig) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let refuse = |tcp: &mut Tcp, path: &str, status, message: &str| refuse_transfer(tcp, state, session, path, Direction::Outgoing, status, message);
+    if packet.resume && !session.capabilities.contains(Capabilities::RESUME) {
+        return refuse(tcp, &requested_path(state, session, &packet.name), FtpStatusCode::NotSupported, ERROR_RESUME_NOT_SUPPORTED);
+    }
+    let path = match resolve_path(&session.root, &session.cwd, &packet.name) {
+        Ok(path) => path,
+        Err(e) => return refuse(tcp, &requested_path(state, session, &packet.name), FtpStatusCode::InvalidPath, &e.to_string()),
+    };
+    let (mut file, metadata) = match File::open(&path).and_then(|file| file.metadata().map(|metadata| (file, metadata))) {
+        Ok((_, metadata)) if metadata.is_dir() => {
+            return refuse(tcp, &served_path(state, &path), FtpStatusCode::InvalidPath, &io_error_message(&std::io::Error::from(ErrorKind::IsADirectory)));
         }
+        Ok(opened) => opened,
+        Err(e) => return refuse(tcp, &served_path(state, &path), FtpStatusCode::from_io_error(&e), &io_error_message(&e)),
     };
-    tcp.write(&ResponsePacket{ status: FtpStatusCode::Ok, message: [0; 150] });
+    tcp.write(&ResponsePacket::ok())?;
+    tcp.write(&FileStatPacket { size: metadata.len(), modified: metadata.mtime() })?;
 
-    let res = tcp.read::<ResponsePacket>();
-    if res.status == FtpStatusCode::Error {
-        println!("{} {}", "Error:".red(), String::from_utf8_lossy(&res.message));
+    let res_packet = tcp.read::<ResponsePacket>()?;
+    if res_packet.status != FtpStatusCode::Ok {
+        warn!("{} Client refused the download: {}", tcp.peer_addr_to_string(), res_packet.message());
+        audit_refusal(tcp, state, session, &served_path(state, &path), Direction::Outgoing, &res_packet.message());
         return Ok(());
     }
 
-    return receive_file(&mut file, udp);
+    let started = Instant::now();
+    let mut transferred = 0;
+    let start_index = if packet.resume { accept_resume(&file, tcp, transfer_config) } else { Ok(0) };
+    let result = start_index.and_then(|start_index| {
+        let offset = start_index * transfer_config.block_size as u64;
+        send_file(&mut file, udp, tcp, transfer_config, start_index, &mut |progress| transferred = progress.transferred.saturating_sub(offset))
+    });
+    audit(tcp, state, session, &path, Direction::Outgoing, started, transferred, &result);
+    return result;
 }
 
-fn get(udp: &mut Udp, tcp: &mut Tcp) -> std::io::Result<()> {
-    let packet = tcp.read::<FileInfoPacket>();
-    let path_string = get_path_from_filename(&packet.name)?;
-    let path = Path::new(path_string.as_str());
-    let mut file = match File::open(path_string) {
-        Ok(file) => file,
-        Err(_) => {
-            let mut error_packet = ResponsePacket { status: FtpStatusCode::Error, message: [0; 150] };
-            let error_message = "This file does not exist".to_string();
-            error_packet.message[..error_message.len()].copy_from_slice(error_message.as_bytes());
-            tcp.write(&error_packet);
+fn list(tcp: &mut Tcp, session: &Session) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let path = match resolve_or_reply(tcp, session, &packet.name)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let entries = match read_entries(&path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tcp.write(&ResponsePacket::new(FtpStatusCode::from_io_error(&e), &io_error_message(&e)))?;
             return Ok(())
         }
     };
-    tcp.write(&ResponsePacket { status: FtpStatusCode::Ok, message: [0; 150] });
+    tcp.write(&ResponsePacket::ok())?;
 
-    let res_packet = tcp.read::<ResponsePacket>();
-    if res_packet.status == FtpStatusCode::Error {
-        println!("{} {}", "Error:".red(), String::from_utf8_lossy(&res_packet.message));
-        return Ok(());
+    let mut entries = entries.into_iter().peekable();
+    loop {
+        let chunk: Vec<ListEntry> = entries.by_ref().take(LIST_CHUNK_SIZE).collect();
+        let is_last = entries.peek().is_none();
+        tcp.write(&ListPacket { entries: chunk, is_last })?;
+        if is_last {
+            return Ok(());
+        }
+    }
+}
+
+/// Lists a directory sorted by name, or describes a single file when `path` is not a directory.
+fn read_entries(path: &Path) -> std::io::Result<Vec<ListEntry>> {
+    let metadata = std::fs::metadata(path)?;
+    if !metadata.is_dir() {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        return Ok(vec![list_entry(name, &metadata)]);
+    }
+
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        entries.push(list_entry(entry.file_name().to_string_lossy().to_string(), &entry.path().symlink_metadata()?));
     }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn list_entry(name: String, metadata: &Metadata) -> ListEntry {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_file() {
+        EntryKind::File
+    } else {
+        EntryKind::Other
+    };
+    return ListEntry { name, kind, size: metadata.len(), modified: metadata.mtime() }
+}
 
-    return send_file(&mut file, udp);
+fn make_dir(tcp: &mut Tcp, session: &Session) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let path = match resolve_entry_or_reply(tcp, session, &packet.name)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    return reply(tcp, std::fs::create_dir(path));
+}
+
+fn remove_dir(tcp: &mut Tcp, session: &Session) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let path = match resolve_entry_or_reply(tcp, session, &packet.name)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    return reply(tcp, std::fs::remove_dir(path));
 }
 
-fn exit(udp: &mut Udp, tcp: &mut Tcp) -> std::io::Result<()> {
-    println!("Connection with {} has been closed", format!("{}", tcp.peer_addr_to_string().underline()).bold());
+fn delete(tcp: &mut Tcp, session: &Session) -> Result<()> {
+    let packet = tcp.read::<FileInfoPacket>()?;
+    let path = match resolve_entry_or_reply(tcp, session, &packet.name)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    return reply(tcp, std::fs::remove_file(path));
+}
+
+fn rename(tcp: &mut Tcp, session: &Session) -> Result<()> {
+    let packet = tcp.read::<RenamePacket>()?;
+    let from = match resolve_entry_or_reply(tcp, session, &packet.from)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let to = match resolve_entry_or_reply(tcp, session, &packet.to)? {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    // Refuse to silently overwrite, the client has to delete the target first.
+    if to.symlink_metadata().is_ok() {
+        return reply(tcp, Err(std::io::Error::from(ErrorKind::AlreadyExists)));
+    }
+    return reply(tcp, std::fs::rename(from, to));
+}
+
+/// Answers a `put` or `get` refused before any data was sent and records it in the audit log.
+fn refuse_transfer(tcp: &mut Tcp, state: &ServerState, session: &Session, path: &str, direction: Direction, status: FtpStatusCode, message: &str) -> Result<()> {
+    let transfer = match direction { Direction::Incoming => "upload", Direction::Outgoing => "download" };
+    warn!("{} Refused the {} of {}: {}", tcp.peer_addr_to_string(), transfer, path, message);
+    audit_refusal(tcp, state, session, path, direction, message);
+    return tcp.write(&ResponsePacket::new(status, message));
+}
+
+/// Appends the outcome of a transfer to the audit log, when there is one. Failing to do so is
+/// logged but doesn't fail the command.
+#[allow(clippy::too_many_arguments)]
+fn audit(tcp: &Tcp, state: &ServerState, session: &Session, path: &Path, direction: Direction, started: Instant, bytes: u64, result: &Result<()>) {
+    let error = result.as_ref().err().map(|e| e.to_string());
+    record_transfer(tcp, state, session, &served_path(state, path), direction, started.elapsed(), bytes, error);
+}
+
+/// Records a `put` or `get` that was refused, and so moved no data, in the audit log.
+fn audit_refusal(tcp: &Tcp, state: &ServerState, session: &Session, path: &str, direction: Direction, message: &str) {
+    record_transfer(tcp, state, session, path, direction, Duration::ZERO, 0, Some(message.to_string()));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn record_transfer(tcp: &Tcp, state: &ServerState, session: &Session, path: &str, direction: Direction, duration: Duration, bytes: u64, error: Option<String>) {
+    let Some(audit_log) = &state.audit_log else { return };
+    let peer = tcp.stream.peer_addr().map_or_else(|_| "unknown".to_string(), |addr| addr.ip().to_string());
+    let record = TransferRecord {
+        time: Local::now(),
+        duration,
+        peer: &peer,
+        user: session.user.as_deref(),
+        anonymous: session.anonymous,
+        path,
+        bytes,
+        direction,
+        error,
+    };
+    if let Err(e) = audit_log.record(&record) {
+        error!("Could not write to the audit log: {}", e);
+    }
+}
+
+/// Where `name`, as sent by the client, points in the served directory, for the audit log of a
+/// refused transfer. Only `.` is dropped, a `..` is kept so that an escape attempt shows as such.
+fn requested_path(state: &ServerState, session: &Session, name: &str) -> String {
+    let mut path = Path::new("/").join(session.root.strip_prefix(&state.root).unwrap_or(Path::new("")));
+    if !name.starts_with('/') {
+        path.push(&session.cwd);
+    }
+    for component in Path::new(name).components() {
+        if let Component::Normal(_) | Component::ParentDir = component {
+            path.push(component);
+        }
+    }
+    return path.to_string_lossy().to_string();
+}
+
+/// `path` relative to the served directory and starting with `/`, as written to the audit log.
+fn served_path(state: &ServerState, path: &Path) -> String {
+    return Path::new("/").join(path.strip_prefix(&state.root).unwrap_or(path)).to_string_lossy().to_string();
+}
+
+/// Answers a command whose only outcome is success or an I/O error.
+fn reply(tcp: &mut Tcp, result: std::io::Result<()>) -> Result<()> {
+    return match result {
+        Ok(()) => tcp.write(&ResponsePacket::ok()),
+        Err(e) => tcp.write(&ResponsePacket::new(FtpStatusCode::from_io_error(&e), &io_error_message(&e))),
+    }
+}
+
+fn exit(tcp: &mut Tcp) -> Result<()> {
+    info!("{} Connection closed", tcp.peer_addr_to_string());
     Ok(())
 }
 
 
-fn get_path_from_filename(filename: &[u8]) -> std::io::Result<String> {
-    let filename_ = String::from_utf8_lossy(filename).clone().to_string();
-    let pwd = std::fs::canonicalize("files/")?;
-    return Ok(format!("{}/{}", pwd.to_str().unwrap(), filename_.trim_matches(char::from(0))).to_string());
-}
\ No newline at end of file
+/// Resolves a path sent by the client inside the user's home directory. When the path is refused
+/// the client is answered with `InvalidPath` and `None` is returned, the command is then over.
+fn resolve_or_reply(tcp: &mut Tcp, session: &Session, name: &str) -> Result<Option<PathBuf>> {
+    return match resolve_path(&session.root, &session.cwd, name) {
+        Ok(path) => Ok(Some(path)),
+        Err(e) => {
+            warn!("{} Refused path {}: {}", tcp.peer_addr_to_string(), name, e);
+            tcp.write(&ResponsePacket::new(FtpStatusCode::InvalidPath, &e.to_string()))?;
+            Ok(None)
+        }
+    }
+}
+
+/// Same as `resolve_or_reply` for commands that create, modify or remove the entry itself, which
+/// must never be the home directory.
+fn resolve_entry_or_reply(tcp: &mut Tcp, session: &Session, name: &str) -> Result<Option<PathBuf>> {
+    let path = resolve_or_reply(tcp, session, name)?;
+    if path.as_ref().is_some_and(|path| *path == session.root) {
+        tcp.write(&ResponsePacket::new(FtpStatusCode::InvalidPath, ERROR_ROOT_DIRECTORY))?;
+        return Ok(None);
+    }
+    return Ok(path);
+}
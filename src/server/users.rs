@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+
+pub struct User {
+    pub name: String,
+    /// Argon2id hash in PHC string format, salt and parameters included.
+    pub password_hash: String,
+    /// Directory the user is confined to, relative to the served directory.
+    pub home: String,
+    pub role: Role,
+}
+
+/// What a command does to the files, each role allows a subset of these.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    /// Download files.
+    Read,
+    /// List directories and move between them.
+    List,
+    /// Upload files and create directories.
+    Write,
+    Rename,
+    /// Delete files and directories.
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Can only browse and download.
+    ReadOnly,
+    /// Can only upload, without seeing what is already there.
+    DropBox,
+    /// Everything but deleting.
+    User,
+    Admin,
+}
+
+impl Role {
+    pub fn allows(self, permission: Permission) -> bool {
+        return match self {
+            Role::ReadOnly => matches!(permission, Permission::Read | Permission::List),
+            Role::DropBox => permission == Permission::Write,
+            Role::User => permission != Permission::Delete,
+            Role::Admin => true,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "readonly" => Ok(Role::ReadOnly),
+            "dropbox" => Ok(Role::DropBox),
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role {}, expected readonly, dropbox, user or admin", s)),
+        }
+    }
+}
+
+/// Accounts allowed to log in, loaded once at startup from a file holding one
+/// `name:hash[:home[:role]]` line per user. The home defaults to the user's name when missing or
+/// empty, the role to `user`. Blank lines and lines starting with `#` are ignored.
+pub struct UserDatabase {
+    users: HashMap<String, User>,
+    /// Verified against when the name is unknown, so that answering takes as long as for a known user.
+    dummy_hash: String,
+}
+
+impl UserDatabase {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("Could not read user database {}: {}", path, e)))?;
+        let mut users = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let user = parse_user(line)
+                .map_err(|message| Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", path, number + 1, message)))?;
+            if users.contains_key(&user.name) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("{}:{}: Duplicate user {}", path, number + 1, user.name)));
+            }
+            users.insert(user.name.clone(), user);
+        }
+        Ok(Self { users, dummy_hash: hash_password("") })
+    }
+
+    /// Returns the user when `password` matches its hash. An unknown name costs a verification too,
+    /// the response time must not tell which users exist.
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<&User> {
+        let user = self.users.get(name);
+        let hash = PasswordHash::new(user.map_or(&self.dummy_hash, |user| &user.password_hash)).ok()?;
+        let verified = Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+        return user.filter(|_| verified);
+    }
+
+    pub fn user_count(&self) -> usize {
+        return self.users.len();
+    }
+}
+
+fn parse_user(line: &str) -> Result<User, String> {
+    let mut fields = line.splitn(4, ':');
+    let name = fields.next().unwrap_or_default();
+    let password_hash = fields.next().ok_or("Expected name:hash[:home[:role]]")?;
+    let home = fields.next().filter(|home| !home.is_empty()).unwrap_or(name);
+    let role = fields.next().map_or(Ok(Role::User), Role::from_str)?;
+    if name.is_empty() {
+        return Err("Empty user name".to_string());
+    }
+    PasswordHash::new(password_hash).map_err(|e| format!("Invalid password hash for {}: {}", name, e))?;
+    return Ok(User { name: name.to_string(), password_hash: password_hash.to_string(), home: home.to_string(), role });
+}
+
+/// Hashes `password` with a fresh random salt, the result is what goes after `name:` in the file.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    return Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string();
+}
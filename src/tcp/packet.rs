@@ -1,10 +1,7 @@
 use serde::{Deserialize, Serialize};
-use crate::core::{CommandId, FtpStatusCode};
+use crate::core::{Capabilities, CAPABILITIES, CommandId, EntryKind, ERROR_PATH_TOO_LONG, FtpStatusCode, MAX_PATH_LENGTH, PROTOCOL_VERSION, TransferConfig};
 use serde_with::{serde_as, Bytes};
-
-pub fn deserialize<T>(data: & Vec<u8>) -> T where T: for<'a> serde::de::Deserialize<'a>, {
-    return bincode::deserialize::<T>(&data[..]).unwrap()
-}
+use crate::error::{Error, Result};
 
 // TCP
 #[serde_as]
@@ -21,26 +18,159 @@ impl CommandPacket {
     }
 }
 
+/// First packet each side sends on connect. Its layout must never change so that peers of any
+/// version can read it.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloPacket {
+    pub version: u32,
+    /// Bits of the `Capabilities` the sender supports, unknown ones are ignored.
+    pub capabilities: u32,
+}
+
+/// The hello of this build.
+impl Default for HelloPacket {
+    fn default() -> Self {
+        return Self { version: PROTOCOL_VERSION, capabilities: CAPABILITIES.bits() }
+    }
+}
+
+impl HelloPacket {
+    /// Fails when the sender of the packet speaks another version of the protocol.
+    pub fn check_version(&self) -> Result<()> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(Error::Protocol(format!("Peer speaks protocol version {}, expected {}", self.version, PROTOCOL_VERSION)));
+        }
+        Ok(())
+    }
+
+    /// Features both this side and the sender of the packet support.
+    pub fn shared_capabilities(&self) -> Capabilities {
+        return CAPABILITIES & Capabilities::from_bits_truncate(self.capabilities);
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UdpConfigPacket {
+    /// Payload size of a `FilePacket`: requested by the client, then the value agreed by the server.
     pub packet_size: usize,
+    /// Blocks in flight at once: the client's, then the smaller of both sides' as agreed by the server.
+    pub window_size: u64,
+    /// UDP port the sender receives blocks and acknowledgements on, each session has its own.
+    pub port: u16,
+    /// Milliseconds before a block is sent again, set by the server, the client's value is ignored.
+    pub ack_timeout_ms: u64,
+    /// Times a block is sent before giving up, set by the server, the client's value is ignored.
+    pub max_retries: u32,
+}
+
+impl UdpConfigPacket {
+    /// The packet describing `config`, sent from `port`.
+    pub fn new(config: &TransferConfig, port: u16) -> Self {
+        return Self {
+            packet_size: config.block_size,
+            window_size: config.window_size,
+            port,
+            ack_timeout_ms: config.ack_timeout.as_millis() as u64,
+            max_retries: config.max_retries,
+        }
+    }
 }
 
-#[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileInfoPacket {
     pub size: u64,
-    #[serde_as(as = "Bytes")]
-    pub name: [u8; 40],
+    /// Path relative to the current directory, or to the home directory when it starts with `/`.
+    pub name: String,
+    /// Continue from the partial file left by a previous attempt instead of starting over.
+    pub resume: bool,
+}
+
+impl FileInfoPacket {
+    pub fn new(name: &str, size: u64, resume: bool) -> Result<Self> {
+        if name.len() > MAX_PATH_LENGTH {
+            return Err(Error::InvalidRequest(ERROR_PATH_TOO_LONG.to_string()));
+        }
+        return Ok(Self { size, name: name.to_string(), resume })
+    }
+}
+
+/// Follows the `Ok` response to a `Get`, describing the file about to be sent.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileStatPacket {
+    pub size: u64,
+    /// Last modification time, in seconds since the Unix epoch.
+    pub modified: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginPacket {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenamePacket {
+    pub from: String,
+    pub to: String,
 }
 
-#[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResponsePacket {
     pub status: FtpStatusCode,
+    /// Details for the user, or the result of the request, e.g. the new directory after `ChangeDir`.
+    pub message: Option<String>,
+}
+
+impl ResponsePacket {
+    pub fn ok() -> Self {
+        return Self { status: FtpStatusCode::Ok, message: None }
+    }
+
+    pub fn new(status: FtpStatusCode, message: &str) -> Self {
+        return Self { status, message: Some(message.to_string()) }
+    }
+
+    /// The message, or a description of the status when there is none.
+    pub fn message(&self) -> String {
+        return self.message.clone().unwrap_or_else(|| self.status.description().to_string());
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChecksumPacket {
+    /// `TransferAborted` when the sender gave up, the digest is then meaningless.
+    pub status: FtpStatusCode,
+    #[serde_as(as = "Bytes")]
+    pub digest: [u8; 32],
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumePacket {
+    /// Byte offset the transfer restarts from, always a multiple of the block size.
+    pub offset: u64,
+    /// SHA-256 of the file's first `offset` bytes.
     #[serde_as(as = "Bytes")]
-    pub message: [u8; 150],
+    pub digest: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Last modification time, in seconds since the Unix epoch.
+    pub modified: i64,
+}
+
+/// One chunk of a directory listing, the server sends them until `is_last` is set.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListPacket {
+    pub entries: Vec<ListEntry>,
+    pub is_last: bool,
 }
 
 
@@ -50,9 +180,8 @@ pub struct ResponsePacket {
 pub struct FilePacket {
     pub index: u64,
     pub is_last: bool,
-    pub data_size: usize,
     #[serde_as(as = "Bytes")]
-    pub data: [u8; 1024],
+    pub data: Vec<u8>,
 }
 
 #[serde_as]
@@ -1,62 +1,58 @@
-use std::fs::File;
 use std::io::{Read, Write};
-use std::mem::size_of;
 use std::net::TcpStream;
-use std::slice;
-use colored::*;
+use log::trace;
+use crate::error::{Error, Result};
+use crate::logging::hex_dump;
+
+/// Size in bytes of the big-endian length header that prefixes every frame.
+pub const FRAME_HEADER_SIZE: usize = 4;
+/// Largest payload accepted on the control channel, anything bigger is treated as a protocol error.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
 pub struct Tcp {
     pub stream: TcpStream,
 }
 
 impl Tcp {
-    pub fn write<T>(&mut self, data: & T) where T: serde::Serialize {
-        let bytes = bincode::serialize(data).unwrap();
-        self.stream.write(bytes.as_slice()).expect("Something went wrong writing command");
-        self.stream.flush().expect("Something went wrong flushing stream");
-        println!("{} {}: {:?}", "TCP Send to".truecolor(252, 148, 3).bold(), self.peer_addr_to_string().underline().bold(), bytes);
+    pub fn write<T>(&mut self, data: & T) -> Result<()> where T: serde::Serialize {
+        return self.write_raw(&bincode::serialize(data)?);
     }
 
-    pub fn read<T>(&mut self) -> T where T: for<'a> serde::de::Deserialize<'a>, {
-        return bincode::deserialize::<T>(&self.read_raw(size_of::<T>())[..]).unwrap();
+    pub fn read<T>(&mut self) -> Result<T> where T: for<'a> serde::de::Deserialize<'a>, {
+        let received = self.read_raw()?;
+        return Ok(bincode::deserialize::<T>(&received[..])?);
     }
 
-    pub fn read_raw(&mut self, size: usize) -> Vec<u8> {
-        let mut received: Vec<u8> = vec![];
-        let mut rx_bytes = [0; 250];
-        loop {
-            let bytes_read = match self.stream.read(&mut rx_bytes) {
-                Ok(bytes_read) => bytes_read,
-                Err(e) => {
-                    println!("TCP: Error: {}", e);
-                    0
-                }
-            };
-
-
-
-            // let bytes_read = self.stream.read(&mut rx_bytes).expect("Tcp: Could not read message");
-            received.extend_from_slice(&rx_bytes[..bytes_read]);
-            if bytes_read < MESSAGE_SIZE {
-                break;
-            }
+    /// Sends `payload` as a single frame: a 4-byte big-endian length followed by the payload itself.
+    pub fn write_raw(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(Error::InvalidRequest(format!("Frame of {} bytes exceeds the {} bytes limit", payload.len(), MAX_FRAME_SIZE)));
         }
-        // let mut line = String::from_utf8_lossy(received).unwrap();
-        println!("{} {}: {:?}", "TCP Receive from".truecolor(252, 190, 3).bold(), self.peer_addr_to_string().underline().bold(), received);
-        return received
-    }
-
-    pub fn peer_addr_to_string(&self) -> String {
-        return self.stream.peer_addr().unwrap().to_string();
+        let header = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).map_err(Error::Connection)?;
+        self.stream.flush().map_err(Error::Connection)?;
+        trace!("Sent {} bytes to {}: {}", payload.len(), self.peer_addr_to_string(), hex_dump(payload));
+        Ok(())
     }
 
-    pub fn local_addr_to_string(&self) -> String {
-        return self.stream.local_addr().unwrap().to_string();
+    /// Reads exactly one frame and returns its payload, however the bytes were split or merged on the wire.
+    pub fn read_raw(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        self.stream.read_exact(&mut header).map_err(Error::Connection)?;
+        let size = u32::from_be_bytes(header) as usize;
+        if size > MAX_FRAME_SIZE {
+            return Err(Error::Protocol(format!("Frame of {} bytes exceeds the {} bytes limit", size, MAX_FRAME_SIZE)));
+        }
+        let mut received = vec![0; size];
+        self.stream.read_exact(&mut received).map_err(Error::Connection)?;
+        trace!("Received {} bytes from {}: {}", size, self.peer_addr_to_string(), hex_dump(&received));
+        return Ok(received);
     }
 
-    fn drop(&mut self) {
-        self.stream.shutdown(std::net::Shutdown::Both).expect("Could not shutdown stream");
+    pub fn peer_addr_to_string(&self) -> String {
+        return self.stream.peer_addr().map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
     }
 }
-
-const MESSAGE_SIZE: usize = 256;
@@ -1,61 +1,57 @@
-use std::mem::size_of;
 use std::net::UdpSocket;
 use std::time::Duration;
-use colored::*;
+use log::{debug, trace};
+use crate::error::Result;
+use crate::logging::hex_dump;
+
+/// Largest payload of a UDP datagram over IPv4.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
 
 pub struct Udp {
     pub socket: UdpSocket,
+    rx_bytes: Vec<u8>,
 }
 
 
 impl Udp {
+    pub fn new(socket: UdpSocket) -> Self {
+        return Self { socket, rx_bytes: vec![0; MAX_DATAGRAM_SIZE] }
+    }
+
     pub fn read<T>(&mut self) -> Option<T> where T: for<'a> serde::de::Deserialize<'a> {
-        return self.read_raw(size_of::<T>()).and_then(|received| bincode::deserialize::<T>(&received[..]).ok())
+        return self.read_raw().and_then(|received| bincode::deserialize::<T>(&received[..]).ok())
     }
 
-    pub fn read_raw(&mut self, size: usize) -> Option<Vec<u8>> {
-        let mut received: Vec<u8> = vec![];
-        let mut rx_bytes: Vec<u8> = vec![0; size + 1];
-        let (bytes_read, peer_addr) = match self.socket.recv_from(&mut rx_bytes) {
-            Ok((bytes_read, peer_addr)) => {
-                println!("UDP: Received {} bytes from {}", bytes_read, peer_addr);
-                (bytes_read, peer_addr)
-            }
-            Err(e) => {
-                if self.socket.read_timeout().expect("Udp socket timeout").is_some() {
-                    println!("UDP: Timeout");
+    pub fn read_raw(&mut self) -> Option<Vec<u8>> {
+        let (bytes_read, peer_addr) = match self.socket.recv_from(&mut self.rx_bytes) {
+            Ok(received) => received,
+            Err(_) => {
+                if self.socket.read_timeout().is_ok_and(|timeout| timeout.is_some()) {
+                    debug!("Timeout");
                 }
                 return None;
             }
         };
-        received.extend_from_slice(&rx_bytes[..bytes_read]);
-        println!("{} {}: {:?}", "UDP Receive from".truecolor(252, 190, 3).bold(), peer_addr.to_string().underline().bold(), received);
+        let received = self.rx_bytes[..bytes_read].to_vec();
+        trace!("Received {} bytes from {}: {}", bytes_read, peer_addr, hex_dump(&received));
         return Some(received);
-
     }
 
-    pub fn write<T>(&mut self, data: & T) where T: serde::Serialize {
-        self.write_raw(bincode::serialize(data).unwrap());
+    pub fn write<T>(&mut self, data: & T) -> Result<()> where T: serde::Serialize {
+        return self.write_raw(bincode::serialize(data)?);
     }
 
-    pub fn write_raw(&mut self, data: Vec<u8>) {
-        self.socket.send(data.as_slice()).expect("couldn't send message");
-        println!("{} {}: {:?}", "UDP Send to".truecolor(252, 148, 3).bold(), self.peer_addr_to_string().underline().bold(), data);
+    pub fn write_raw(&mut self, data: Vec<u8>) -> Result<()> {
+        self.socket.send(data.as_slice())?;
+        trace!("Sent {} bytes to {}: {}", data.len(), self.peer_addr_to_string(), hex_dump(&data));
+        Ok(())
     }
 
     pub fn peer_addr_to_string(&self) -> String {
-        return self.socket.peer_addr().unwrap().to_string();
-    }
-
-    pub fn local_addr_to_string(&self) -> String {
-        return self.socket.local_addr().unwrap().to_string();
-    }
-
-    pub fn set_read_timeout(&self, dur: Option<Duration>) {
-        self.socket.set_read_timeout(dur).expect("set_read_timeout call failed")
+        return self.socket.peer_addr().map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
     }
 
-    fn drop(&mut self) {
-        println!("UDP: Dropping socket");
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        return Ok(self.socket.set_read_timeout(dur)?);
     }
 }
\ No newline at end of file